@@ -5,13 +5,24 @@ import { StripeWelcomeEmail } from '../emails/stripe-welcome';
 import { PlaidVerifyIdentityEmail } from '../emails/plaid-verify-identity';
 import { NotionMagicLinkEmail } from '../emails/notion-magic-link';
 import { VercelInviteUserEmail } from '../emails/vercel-invite-user';
+import { existsSync, readFileSync } from 'node:fs';
+
+// Certificate the server generated on first start: ./tls with docker-compose,
+// packages/server/tls with `bun run dev`, or SMTP_TLS_CA when it uses its own
+const caPath = process.env.SMTP_TLS_CA
+  ?? ['../../tls/cert.pem', '../server/tls/cert.pem'].find((path) => existsSync(path));
+if (!caPath) {
+  console.error('❌ No server certificate found, start the server once or set SMTP_TLS_CA');
+  process.exit(1);
+}
 
 const transporter = nodemailer.createTransport({
   host: '127.0.0.1',
   port: 1025,
   secure: false,
   tls: {
-    rejectUnauthorized: false,
+    ca: readFileSync(caPath),
+    servername: 'localhost',
   },
 });
 
@@ -0,0 +1,159 @@
+use crate::db::DbError;
+use crate::query::QueryError;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::fmt;
+use utoipa::ToSchema;
+
+/// Error answered by the REST API, rendered as JSON `{"code": ..., "message": ...}`.
+/// Codes are stable and meant to be matched by clients, messages are for humans.
+#[derive(Debug)]
+pub enum ApiError {
+    MailNotFound(i64),
+    WebhookNotFound(i64),
+    ChaosRuleNotFound(i64),
+    /// Anything else that does not exist, e.g. the source of a mail stored without it
+    NotFound(String),
+    /// Invalid path, query parameters or body
+    BadRequest(String),
+    /// Search query that cannot be parsed, see `query::parse`
+    InvalidQuery(QueryError),
+    /// Nothing happened in time, for long-polling endpoints
+    Timeout(String),
+    /// Unexpected failure. The cause is logged, clients only get a generic message.
+    Internal(String),
+}
+
+/// Body of an error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::MailNotFound(_)
+            | ApiError::WebhookNotFound(_)
+            | ApiError::ChaosRuleNotFound(_)
+            | ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) | ApiError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            ApiError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MailNotFound(_) => "mail_not_found",
+            ApiError::WebhookNotFound(_) => "webhook_not_found",
+            ApiError::ChaosRuleNotFound(_) => "chaos_rule_not_found",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let message = match self {
+            ApiError::Internal(_) => "Internal server error".to_string(),
+            other => other.to_string(),
+        };
+        ErrorBody {
+            code: self.code(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::MailNotFound(id) => write!(f, "Mail {} not found", id),
+            ApiError::WebhookNotFound(id) => write!(f, "Webhook {} not found", id),
+            ApiError::ChaosRuleNotFound(id) => write!(f, "Chaos rule {} not found", id),
+            ApiError::NotFound(message)
+            | ApiError::BadRequest(message)
+            | ApiError::Timeout(message)
+            | ApiError::Internal(message) => f.write_str(message),
+            ApiError::InvalidQuery(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(cause) = &self {
+            eprintln!("[API] Internal error: {}", cause);
+        }
+        (self.status(), axum::Json(self.body())).into_response()
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for ApiError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ApiError::Internal(format!("task failed: {}", e))
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(e: QueryError) -> Self {
+        ApiError::InvalidQuery(e)
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(e: PathRejection) -> Self {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(e: QueryRejection) -> Self {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> Self {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+/// `axum::extract::Path`, rejecting invalid segments with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+/// `axum::extract::Query`, rejecting invalid parameters with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+/// `axum::Json`, rejecting invalid bodies with an `ApiError`
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Result of a REST handler
+pub type ApiResult<T> = Result<T, ApiError>;
@@ -0,0 +1,155 @@
+//! Failure injection ("chaos mode"): rules managed through the REST API that
+//! make the SMTP handler answer with an error instead of accepting a mail, or
+//! answer late, and the latency applied to every session by the SMTP server.
+//!
+//! Rules are kept in memory and checked in creation order, the first one
+//! matching a mail at a given stage answers for it. Rules limited to a number of
+//! mails count each message once, however many of its stages they fired at.
+
+use crate::models::{ChaosRule, ChaosStage, Latency};
+use mailin::Response;
+use regex::{Regex, RegexBuilder};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Chaos {
+    rules: Mutex<Vec<ActiveRule>>,
+    next_id: AtomicI64,
+    latency: Mutex<Latency>,
+}
+
+/// What a rule that fired asks of the session
+#[derive(Debug)]
+pub struct Fired {
+    /// Id of the rule, to `spend` once the message is done with
+    pub rule_id: i64,
+    /// Error to answer with, the mail goes on when missing
+    pub reply: Option<Response>,
+    pub delay: Duration,
+    /// Upload rate of the content, for rules checked at DATA
+    pub bytes_per_second: Option<u64>,
+}
+
+struct ActiveRule {
+    rule: ChaosRule,
+    recipient: Option<Regex>,
+    sender: Option<Regex>,
+}
+
+impl ActiveRule {
+    fn matches(&self, stage: ChaosStage, sender: &str, recipients: &[String]) -> bool {
+        self.rule.stage == stage
+            && self.sender.as_ref().is_none_or(|pattern| pattern.is_match(sender))
+            && self
+                .recipient
+                .as_ref()
+                .is_none_or(|pattern| recipients.iter().any(|to| pattern.is_match(to)))
+    }
+}
+
+impl Chaos {
+    pub fn rules(&self) -> Vec<ChaosRule> {
+        self.lock().iter().map(|active| active.rule.clone()).collect()
+    }
+
+    /// Add `rule` after the existing ones, answering it with its assigned id
+    pub fn add(&self, mut rule: ChaosRule) -> Result<ChaosRule, regex::Error> {
+        let recipient = rule.recipient.as_deref().map(pattern).transpose()?;
+        let sender = rule.sender.as_deref().map(pattern).transpose()?;
+        rule.id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.lock().push(ActiveRule {
+            rule: rule.clone(),
+            recipient,
+            sender,
+        });
+        Ok(rule)
+    }
+
+    /// Whether a rule with this id existed
+    pub fn remove(&self, id: i64) -> bool {
+        let mut rules = self.lock();
+        let before = rules.len();
+        rules.retain(|active| active.rule.id != id);
+        rules.len() != before
+    }
+
+    /// Remove every rule, answering how many there were
+    pub fn clear(&self) -> usize {
+        std::mem::take(&mut *self.lock()).len()
+    }
+
+    /// What the first rule firing at `stage` for a mail from `sender` to
+    /// `recipients` asks for. The fired rule only counts once it is spent.
+    pub fn check(&self, stage: ChaosStage, sender: &str, recipients: &[String]) -> Option<Fired> {
+        let rules = self.lock();
+        let rule = &rules
+            .iter()
+            .find(|active| {
+                active.matches(stage, sender, recipients)
+                    && active.rule.percent.is_none_or(|percent| roll() < percent)
+            })?
+            .rule;
+        println!("[Chaos] Rule {} fired at {:?}", rule.id, stage);
+        Some(Fired {
+            rule_id: rule.id,
+            reply: rule
+                .code
+                .map(|code| Response::custom(code, rule.message.clone().unwrap_or_default())),
+            delay: Duration::from_millis(rule.delay_ms.unwrap_or_default()),
+            bytes_per_second: rule.bytes_per_second,
+        })
+    }
+
+    /// Count one more mail for each of the rules `ids`, which fired for it.
+    /// Rules limited to a number of mails are removed once spent.
+    pub fn spend(&self, ids: &[i64]) {
+        let mut rules = self.lock();
+        for id in ids {
+            let Some(index) = rules.iter().position(|active| active.rule.id == *id) else {
+                continue;
+            };
+            if let Some(remaining) = rules[index].rule.remaining.as_mut() {
+                *remaining = remaining.saturating_sub(1);
+                if *remaining == 0 {
+                    rules.remove(index);
+                }
+            }
+        }
+    }
+
+    pub fn latency(&self) -> Latency {
+        self.latency.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Applies to the next replies of sessions already open too
+    pub fn set_latency(&self, latency: Latency) {
+        *self.latency.lock().unwrap_or_else(|e| e.into_inner()) = latency;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<ActiveRule>> {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Addresses are matched case-insensitively, anywhere in the address unless anchored
+fn pattern(source: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(source).case_insensitive(true).build()
+}
+
+/// Uniform sample in `0..100`. Randomly keyed std hashers are plenty for this.
+fn roll() -> f64 {
+    let sample = RandomState::new().build_hasher().finish();
+    sample as f64 / u64::MAX as f64 * 100.0
+}
+
+/// Wait as long as moving `bytes` takes at `bytes_per_second`
+pub fn throttle(bytes: usize, bytes_per_second: u64) {
+    if bytes_per_second > 0 {
+        thread::sleep(Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64));
+    }
+}
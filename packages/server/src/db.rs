@@ -1,41 +1,229 @@
 use rusqlite::Connection;
+use std::fmt;
 
-/// Initialize the database with the necessary tables
-pub fn init_db(db_path: &str) -> Result<Connection, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-
-    // Create mails table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS mails (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            from_address TEXT,
-            from_name TEXT,
-            to_address TEXT,
-            to_name TEXT,
-            subject TEXT,
-            html TEXT,
-            text TEXT,
-            date TEXT,
-            is_read INTEGER DEFAULT 0
-        )",
-        [],
-    )?;
+/// One step of the schema
+struct Migration {
+    /// `(table, column definition)` pairs, each added only when the table lacks
+    /// it: builds from before versioning created some in `CREATE TABLE` directly
+    columns: &'static [(&'static str, &'static str)],
+    /// Statements run once the columns exist
+    sql: &'static str,
+}
 
-    // Create attachments table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS attachments (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            mail_id INTEGER NOT NULL,
-            filename TEXT NOT NULL,
-            content_type TEXT NOT NULL,
-            content_disposition TEXT,
-            size_bytes INTEGER NOT NULL,
-            file_url TEXT NOT NULL,
-            FOREIGN KEY(mail_id) REFERENCES mails(id) ON DELETE CASCADE
-        )",
-        [],
-    )?;
+impl Migration {
+    const fn sql(sql: &'static str) -> Self {
+        Self { columns: &[], sql }
+    }
+}
+
+/// Ordered schema migrations. Entry `n` brings the schema to version `n + 1`,
+/// and SQLite's `user_version` records the last one applied. Never edit a
+/// migration once released, append a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    // 1: initial schema, as shipped before versioning existed
+    Migration::sql("CREATE TABLE IF NOT EXISTS mails (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        from_address TEXT,
+        from_name TEXT,
+        to_address TEXT,
+        to_name TEXT,
+        subject TEXT,
+        html TEXT,
+        text TEXT,
+        date TEXT,
+        is_read INTEGER DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS attachments (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mail_id INTEGER NOT NULL,
+        filename TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        content_disposition TEXT,
+        size_bytes INTEGER NOT NULL,
+        file_url TEXT NOT NULL,
+        FOREIGN KEY(mail_id) REFERENCES mails(id) ON DELETE CASCADE
+    );"),
+    // 2: raw source, parse warnings and SMTP envelope
+    Migration {
+        columns: &[
+            ("mails", "raw BLOB"),
+            ("mails", "parse_warnings TEXT"),
+            ("mails", "helo_domain TEXT"),
+            ("mails", "envelope_from TEXT"),
+            ("mails", "envelope_to TEXT"),
+            ("mails", "is_8bit INTEGER DEFAULT 0"),
+        ],
+        sql: "",
+    },
+    // 3: recipients from every address header
+    Migration::sql("CREATE TABLE IF NOT EXISTS recipients (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mail_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        name TEXT NOT NULL,
+        address TEXT NOT NULL,
+        FOREIGN KEY(mail_id) REFERENCES mails(id) ON DELETE CASCADE
+    );
+    CREATE INDEX IF NOT EXISTS idx_recipients_address ON recipients(address);"),
+    // 4: full header list, in message order
+    Migration::sql("CREATE TABLE IF NOT EXISTS headers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mail_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        raw_value TEXT NOT NULL,
+        value TEXT NOT NULL,
+        FOREIGN KEY(mail_id) REFERENCES mails(id) ON DELETE CASCADE
+    );"),
+    // 5: full-text index over subject, sender, bodies and attachment names, filled
+    // by the storage layer; rows go away with their mail
+    Migration::sql("CREATE VIRTUAL TABLE mails_fts USING fts5(
+        subject, sender, body, html, attachments,
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+    CREATE TRIGGER mails_fts_delete AFTER DELETE ON mails BEGIN
+        DELETE FROM mails_fts WHERE rowid = old.id;
+    END;"),
+    // 6: webhook targets and their delivery log, kept after a target is removed
+    Migration::sql("CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL,
+        secret TEXT,
+        events TEXT NOT NULL,
+        created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        event TEXT NOT NULL,
+        mail_id INTEGER NOT NULL,
+        attempt INTEGER NOT NULL,
+        status INTEGER,
+        error TEXT,
+        duration_ms INTEGER NOT NULL,
+        delivered_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );
+    CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries(webhook_id);"),
+    // 7: log of published events, replayed to clients resuming an event stream
+    Migration::sql("CREATE TABLE events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        mail_id INTEGER,
+        created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );"),
+    // 8: identity the SMTP session authenticated as
+    Migration::sql("ALTER TABLE mails ADD COLUMN auth_user TEXT;"),
+    // 9: TLS parameters of the SMTP session, NULL for plaintext
+    Migration::sql("ALTER TABLE mails ADD COLUMN tls_protocol TEXT;
+    ALTER TABLE mails ADD COLUMN tls_cipher TEXT;"),
+    // 10: recipients of the mails received before they were recorded, from the
+    // only address kept in the mail itself
+    Migration::sql("INSERT INTO recipients (mail_id, kind, name, address)
+    SELECT id, 'to', COALESCE(to_name, ''), to_address FROM mails
+    WHERE COALESCE(to_address, '') != ''
+        AND NOT EXISTS (SELECT 1 FROM recipients r WHERE r.mail_id = mails.id);"),
+    // 11: deliveries of events about the whole mailbox have no mail
+    Migration::sql("CREATE TABLE webhook_deliveries_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        event TEXT NOT NULL,
+        mail_id INTEGER,
+        attempt INTEGER NOT NULL,
+        status INTEGER,
+        error TEXT,
+        duration_ms INTEGER NOT NULL,
+        delivered_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+    );
+    INSERT INTO webhook_deliveries_new SELECT * FROM webhook_deliveries;
+    DROP TABLE webhook_deliveries;
+    ALTER TABLE webhook_deliveries_new RENAME TO webhook_deliveries;
+    CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries(webhook_id);"),
+];
+
+/// Schema version this build knows how to use
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    /// The database was written by a newer version of the server
+    SchemaTooNew { found: i64, supported: i64 },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "database error: {}", e),
+            DbError::Pool(e) => write!(f, "connection pool error: {}", e),
+            DbError::SchemaTooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the supported version {}, refusing to start",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
 
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(e: r2d2::Error) -> Self {
+        DbError::Pool(e)
+    }
+}
+
+/// Initialize the database, applying any pending migrations
+pub fn init_db(db_path: &str) -> Result<Connection, DbError> {
+    let mut conn = Connection::open(db_path)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut conn)?;
     Ok(conn)
 }
 
+/// Bring the schema up to `SCHEMA_VERSION`, one transaction per migration
+pub fn migrate(conn: &mut Connection) -> Result<(), DbError> {
+    let current: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if current > SCHEMA_VERSION {
+        return Err(DbError::SchemaTooNew {
+            found: current,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index as i64 + 1;
+        let tx = conn.transaction()?;
+        for (table, column) in migration.columns {
+            add_column_if_missing(&tx, table, column)?;
+        }
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        println!("[DB] Applied migration {}", version);
+    }
+
+    Ok(())
+}
+
+/// `ALTER TABLE table ADD COLUMN column`, unless the table already has it
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str) -> Result<(), DbError> {
+    let name = column.split_whitespace().next().unwrap_or_default();
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        [table, name],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {};", table, column))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::db::DbError;
+use crate::models::MailEvent;
+use crate::storage::Storage;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// A mail event numbered in publication order
+#[derive(Debug, Clone)]
+pub struct BusEvent {
+    pub id: i64,
+    pub event: MailEvent,
+}
+
+/// An event as sent to SSE and WebSocket clients
+#[derive(Debug, Clone, Serialize)]
+pub struct WireEvent {
+    pub id: i64,
+    pub event: &'static str,
+    pub data: serde_json::Value,
+}
+
+impl BusEvent {
+    pub fn to_wire(&self) -> WireEvent {
+        let data = match &self.event {
+            MailEvent::Created(mail) | MailEvent::Updated(mail) => {
+                serde_json::to_value(mail).unwrap_or_default()
+            }
+            MailEvent::Deleted(mail) => serde_json::json!({ "id": mail.id }),
+            MailEvent::Cleared => serde_json::json!({}),
+            MailEvent::DeletedMany(ids) => serde_json::json!({ "ids": ids }),
+            MailEvent::UpdatedMany { ids, is_read } => {
+                serde_json::json!({ "ids": ids, "is_read": is_read })
+            }
+        };
+        WireEvent {
+            id: self.id,
+            event: self.event.name(),
+            data,
+        }
+    }
+}
+
+/// Publishes mail events to live subscribers, after logging them in the
+/// database so that clients can resume from the last event they saw. Cheap to clone.
+#[derive(Clone)]
+pub struct EventBus {
+    storage: Storage,
+    sender: broadcast::Sender<BusEvent>,
+    // Keeps ids in broadcast order when several threads publish at once
+    publishing: Arc<Mutex<()>>,
+}
+
+impl EventBus {
+    pub fn new(storage: Storage, capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            storage,
+            sender,
+            publishing: Arc::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Log and broadcast an event, returning its id. Blocks on the database.
+    pub fn publish(&self, event: MailEvent) -> Result<i64, DbError> {
+        let _guard = self.publishing.lock().unwrap_or_else(|e| e.into_inner());
+        let id = self
+            .storage
+            .record_event(event.name(), event.mail().map(|mail| mail.id))?;
+        // Nobody listening is fine
+        let _ = self.sender.send(BusEvent { id, event });
+        Ok(id)
+    }
+
+    /// Events published after `after_id`, rebuilt from the log with the current
+    /// state of their mail. `None` when they cannot all be replayed, because the
+    /// log was pruned past `after_id`, `after_id` comes from another database or
+    /// a bulk event is among them; the client should then reload everything.
+    /// Blocks on the database.
+    pub fn replay(&self, after_id: i64) -> Result<Option<Vec<WireEvent>>, DbError> {
+        let Some(logged) = self.storage.events_after(after_id)? else {
+            return Ok(None);
+        };
+        let mut events = Vec::with_capacity(logged.len());
+        for logged in logged {
+            if MailEvent::BULK_NAMES.contains(&logged.name.as_str()) {
+                return Ok(None);
+            }
+            let Some(event) = MailEvent::NAMES.iter().find(|name| **name == logged.name) else {
+                continue;
+            };
+            let data = match (*event, logged.mail_id) {
+                ("mail.deleted", Some(mail_id)) => serde_json::json!({ "id": mail_id }),
+                (_, Some(mail_id)) => match self.storage.get_mail(mail_id)? {
+                    Some(mail) => serde_json::to_value(mail).unwrap_or_default(),
+                    // Deleted since, a later mail.deleted event says so
+                    None => continue,
+                },
+                (_, None) => serde_json::json!({}),
+            };
+            events.push(WireEvent {
+                id: logged.id,
+                event,
+                data,
+            });
+        }
+        Ok(Some(events))
+    }
+}
@@ -0,0 +1,170 @@
+use crate::html;
+use crate::models::{ExtractedLink, StoredMail};
+use regex::Regex;
+
+/// Digit runs considered codes when no pattern is given
+pub const DEFAULT_CODE_LENGTHS: (usize, usize) = (4, 8);
+
+/// How to recognise one-time codes in a mail
+pub enum CodePattern {
+    /// Standalone runs of digits with a length in the inclusive range
+    Digits { min: usize, max: usize },
+    /// A regular expression, the first capture group is the code when present
+    Regex(Regex),
+}
+
+/// Every link in a mail: `href`s of the HTML body with their anchor text, then
+/// URLs found in the text body. Duplicates within a source are dropped.
+pub fn links(mail: &StoredMail) -> Vec<ExtractedLink> {
+    let mut links: Vec<ExtractedLink> = Vec::new();
+
+    for (url, text) in html_anchors(&mail.html) {
+        if !links.iter().any(|link| link.url == url) {
+            links.push(ExtractedLink {
+                url,
+                text,
+                source: "html".to_string(),
+            });
+        }
+    }
+
+    let mut seen_in_text: Vec<String> = Vec::new();
+    for url in text_urls(&mail.text) {
+        if !seen_in_text.contains(&url) {
+            seen_in_text.push(url.clone());
+            links.push(ExtractedLink {
+                url,
+                text: String::new(),
+                source: "text".to_string(),
+            });
+        }
+    }
+    links
+}
+
+/// Codes found in the subject, the text body and the visible HTML text, in that
+/// order and without duplicates
+pub fn codes(mail: &StoredMail, pattern: &CodePattern) -> Vec<String> {
+    let mut codes: Vec<String> = Vec::new();
+    let sources = [
+        mail.subject.clone(),
+        mail.text.clone(),
+        html::strip_tags(&mail.html),
+    ];
+
+    for source in &sources {
+        let found: Vec<String> = match pattern {
+            CodePattern::Digits { min, max } => digit_runs(source)
+                .filter(|run| (*min..=*max).contains(&run.len()))
+                .map(str::to_string)
+                .collect(),
+            CodePattern::Regex(regex) => regex
+                .captures_iter(source)
+                .filter_map(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .map(|m| m.as_str().to_string())
+                .collect(),
+        };
+        for code in found {
+            if !codes.contains(&code) {
+                codes.push(code);
+            }
+        }
+    }
+    codes
+}
+
+/// Words made only of ASCII digits, so `code: 123456` yields `123456` but
+/// `abc123` yields nothing
+fn digit_runs(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// `(href, anchor text)` for every `<a>` element with an `href`
+fn html_anchors(html: &str) -> Vec<(String, String)> {
+    let lower = html.to_ascii_lowercase();
+    let mut anchors = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = lower[pos..].find("<a").map(|i| pos + i) {
+        let after_name = lower.as_bytes().get(start + 2).copied();
+        let Some(tag_end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        pos = tag_end + 1;
+        if !matches!(after_name, Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            continue;
+        }
+        let Some(href) = attribute(&html[start..tag_end], "href") else {
+            continue;
+        };
+        let content_end = lower[pos..].find("</a").map_or(html.len(), |i| pos + i);
+        let text = html::strip_tags(&html[pos..content_end]);
+        anchors.push((href, text));
+        pos = content_end;
+    }
+    anchors
+}
+
+/// Value of an attribute inside a start tag, with character references decoded
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(name).map(|i| search + i) {
+        search = found + name.len();
+        let preceded_by_space = lower[..found].ends_with(|c: char| c.is_whitespace());
+        let rest = lower[search..].trim_start();
+        if !preceded_by_space || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let raw = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default(),
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default(),
+        };
+        let mut decoded = String::new();
+        html::push_decoded(&mut decoded, raw.trim());
+        return Some(decoded);
+    }
+    None
+}
+
+/// `http(s)://` URLs in plain text, without trailing punctuation
+fn text_urls(text: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = ["http://", "https://"]
+        .iter()
+        .filter_map(|scheme| rest.find(scheme))
+        .min()
+    {
+        let candidate = &rest[start..];
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'' | '`'))
+            .unwrap_or(candidate.len());
+        let mut url = &candidate[..end];
+        // Drop sentence punctuation and an unbalanced closing bracket
+        loop {
+            let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+            let trimmed = if trimmed.ends_with(')') && !trimmed.contains('(') {
+                &trimmed[..trimmed.len() - 1]
+            } else {
+                trimmed
+            };
+            if trimmed.len() == url.len() {
+                break;
+            }
+            url = trimmed;
+        }
+        if url.len() > "https://".len() {
+            urls.push(url.to_string());
+        }
+        rest = &candidate[end..];
+    }
+    urls
+}
@@ -0,0 +1,82 @@
+/// Reduce an HTML document to its visible text, for indexing and previews.
+/// Not a full parser: tags are dropped, `<script>`/`<style>` contents are
+/// skipped and the most common entities are decoded.
+pub fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        push_decoded(&mut text, &rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[1..end].to_ascii_lowercase();
+        let closing_tag = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        rest = &rest[end + 1..];
+
+        if !closing_tag && (name == "script" || name == "style") {
+            let closing = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(pos) => &rest[pos..],
+                None => "",
+            };
+        } else if !text.ends_with(' ') {
+            // Block boundaries and line breaks separate words
+            text.push(' ');
+        }
+    }
+    push_decoded(&mut text, rest);
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Append `raw` to `out`, decoding character references
+pub fn push_decoded(out: &mut String, raw: &str) {
+    let mut rest = raw;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
@@ -1,102 +1,307 @@
-use crate::models::{Attachment, StoredMail};
-use std::borrow::Cow;
-use tokio::{io, sync::broadcast};
-use mail_parser::{Message, MessageParser, MimeHeaders};
-use mailin_embedded::{Handler, Response, response::OK};
-use rusqlite::Connection;
+use crate::chaos::{self, Chaos};
+use crate::events::EventBus;
+use crate::models::{
+    Attachment, ChaosStage, Envelope, MailEvent, MailHeader, Recipient, StoredMail, TlsInfo,
+};
+use crate::smtp_auth::SmtpAuth;
+use crate::storage::Storage;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io;
+use mail_parser::{
+    Addr, Address, DateTime, Header, HeaderValue, Message, MessageParser, MimeHeaders,
+};
+use mailin::{Handler, Response, response::{AUTH_OK, INTERNAL_ERROR, INVALID_CREDENTIALS, OK}};
+
+/// Where the handler leaves its refusal of a message, for the server to send as
+/// the reply to the end of data. mailin stays in DATA after an error reply
+/// there and takes the next commands for content, so `data_end` always accepts.
+pub type Refusal = Arc<Mutex<Option<Response>>>;
 
 #[derive(Clone)]
 pub struct MailinHandler {
     buffer: Vec<u8>,
-    db_path: String,
-    sender: broadcast::Sender<StoredMail>,
+    envelope: Envelope,
+    storage: Storage,
+    events: EventBus,
+    auth: Arc<SmtpAuth>,
+    // Username the session authenticated as, kept across the mails it sends
+    auth_user: Option<String>,
+    // Set by the server once the connection is encrypted
+    tls: Arc<OnceLock<TlsInfo>>,
+    chaos: Arc<Chaos>,
+    // Chaos rules fired during the current transaction, spent when it ends
+    fired: Vec<i64>,
+    // Sender of the current transaction, for chaos rules checked at RCPT
+    mail_from: String,
+    refusal: Refusal,
+    // Upload rate imposed by a chaos rule on the current message
+    data_rate: Option<u64>,
 }
 
 impl MailinHandler {
-    pub fn new(db_path: String, sender: broadcast::Sender<StoredMail>) -> Self {
+    pub fn new(storage: Storage, events: EventBus) -> Self {
         Self {
             buffer: Vec::new(),
-            db_path,
-            sender,
+            envelope: Envelope::default(),
+            storage,
+            events,
+            auth: Arc::default(),
+            auth_user: None,
+            tls: Arc::default(),
+            chaos: Arc::default(),
+            fired: Vec::new(),
+            mail_from: String::new(),
+            refusal: Refusal::default(),
+            data_rate: None,
         }
     }
+
+    /// Check SMTP AUTH credentials against `auth`. The server must also offer AUTH.
+    pub fn with_auth(mut self, auth: Arc<SmtpAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Record the TLS parameters set in `tls` on the mails of this session
+    pub fn with_tls(mut self, tls: Arc<OnceLock<TlsInfo>>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Leave the refusals of messages in `refusal` instead of answering them
+    pub fn with_refusal(mut self, refusal: Refusal) -> Self {
+        self.refusal = refusal;
+        self
+    }
+
+    /// Answer with the errors injected by the rules in `chaos`
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Apply the chaos rule firing at `stage`, if any, answering its error
+    fn inject(&mut self, stage: ChaosStage, from: &str, to: &[String]) -> Option<Response> {
+        let fired = self.chaos.check(stage, from, to)?;
+        if !self.fired.contains(&fired.rule_id) {
+            self.fired.push(fired.rule_id);
+        }
+        thread::sleep(fired.delay);
+        if stage == ChaosStage::Data {
+            self.data_rate = fired.bytes_per_second;
+        }
+        fired.reply
+    }
+
+    /// Count the current transaction against the chaos rules that fired during it
+    fn spend_fired(&mut self) {
+        self.chaos.spend(&std::mem::take(&mut self.fired));
+    }
+
+    fn authenticate(&mut self, username: &str, password: &str) -> Response {
+        if !self.auth.accepts(username, password) {
+            println!("[SMTP] Rejected credentials for `{}`", username);
+            return INVALID_CREDENTIALS;
+        }
+        self.auth_user = Some(username.to_string());
+        AUTH_OK
+    }
 }
 
 impl Handler for MailinHandler {
+    fn mail(&mut self, _ip: IpAddr, _domain: &str, from: &str) -> Response {
+        // A transaction abandoned before the end of data, e.g. after a refused RCPT
+        self.spend_fired();
+        self.mail_from = from.to_string();
+        OK
+    }
+
+    fn rcpt(&mut self, to: &str) -> Response {
+        let from = self.mail_from.clone();
+        self.inject(ChaosStage::Rcpt, &from, &[to.to_string()]).unwrap_or(OK)
+    }
+
     fn data_start(
         &mut self,
-        _domain: &str,
-        _from: &str,
-        _is8bit: bool,
-        _to: &[String],
+        domain: &str,
+        from: &str,
+        is8bit: bool,
+        to: &[String],
     ) -> Response {
+        self.data_rate = None;
+        if let Some(response) = self.inject(ChaosStage::Data, from, to) {
+            return response;
+        }
         self.buffer.clear();
+        self.envelope = Envelope {
+            helo_domain: domain.to_string(),
+            mail_from: from.to_string(),
+            rcpt_to: to.to_vec(),
+            is_8bit: is8bit,
+            auth_user: self.auth_user.clone(),
+            tls: self.tls.get().cloned(),
+        };
         OK
     }
 
     fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(bytes_per_second) = self.data_rate {
+            chaos::throttle(buf.len(), bytes_per_second);
+        }
         self.buffer.extend_from_slice(buf);
         Ok(())
     }
 
     fn data_end(&mut self) -> Response {
-        let full_message: String = String::from_utf8_lossy(&self.buffer).to_string();
-        let message: Message = MessageParser::default().parse(&full_message).unwrap();
-        let conn = Connection::open(&self.db_path).unwrap();
+        let response = self.finish_message();
+        self.spend_fired();
+        if response.is_error {
+            *self.refusal.lock().unwrap_or_else(|e| e.into_inner()) = Some(response);
+        }
+        OK
+    }
 
-        let from = message.from().unwrap().first().unwrap();
-        let to = message.to().unwrap().first().unwrap();
+    fn auth_plain(
+        &mut self,
+        _authorization_id: &str,
+        authentication_id: &str,
+        password: &str,
+    ) -> Response {
+        self.authenticate(authentication_id, password)
+    }
+
+    fn auth_login(&mut self, username: &str, password: &str) -> Response {
+        self.authenticate(username, password)
+    }
+}
 
-        let from_address = from.address.as_ref().unwrap().to_string();
-        let from_name = from.name.as_ref().unwrap_or(&Cow::Borrowed(""));
-        let to_address = to.address.as_ref().unwrap().to_string();
-        let to_name = to.name.as_ref().unwrap_or(&Cow::Borrowed(""));
+/// A session closed in the middle of a transaction still counts against its rules
+impl Drop for MailinHandler {
+    fn drop(&mut self) {
+        self.spend_fired();
+    }
+}
+
+impl MailinHandler {
+    /// Store the received message unless a chaos rule refuses it
+    fn finish_message(&mut self) -> Response {
+        let envelope = self.envelope.clone();
+        if let Some(response) = self.inject(ChaosStage::DataEnd, &envelope.mail_from, &envelope.rcpt_to) {
+            return response;
+        }
+        let mail = match self.store_message() {
+            Ok(mail) => mail,
+            Err(response) => return response,
+        };
+        // Notify event stream subscribers and webhooks, the mail is stored either way
+        if let Err(e) = self.events.publish(MailEvent::Created(mail)) {
+            eprintln!("Failed to publish new mail event: {}", e);
+        }
+        OK
+    }
+
+    /// Parse the buffered message and persist it, answering with an SMTP error
+    /// response instead of panicking when something goes wrong
+    fn store_message(&self) -> Result<StoredMail, Response> {
+        if self.buffer.is_empty() {
+            return Err(unparseable());
+        }
+        let message: Message = MessageParser::default()
+            .parse(&self.buffer)
+            .ok_or_else(unparseable)?;
+        let mut parse_warnings = Vec::new();
 
-        let subject = message.subject().unwrap().to_string();
+        let from = message.from().and_then(|from| from.first());
+        if from.is_none() {
+            parse_warnings.push("Missing From header".to_string());
+        }
+        let to = message.to().and_then(|to| to.first());
+        if to.is_none() {
+            parse_warnings.push("Missing To header".to_string());
+        }
+
+        let from_address = address_of(from);
+        let from_name = name_of(from);
+        let to_address = address_of(to);
+        let to_name = name_of(to);
+
+        let subject = match message.subject() {
+            Some(subject) => subject.to_string(),
+            None => {
+                parse_warnings.push("Missing Subject header".to_string());
+                String::new()
+            }
+        };
         let html = message
             .body_html(0)
             .map(|s| s.to_string())
-            .unwrap_or_else(|| String::new());
+            .unwrap_or_default();
         let text = message
             .body_text(0)
             .map(|s| s.to_string())
-            .unwrap_or_else(|| String::new());
-        let date = message.date().unwrap().to_rfc3339();
-
-        // Insert mail record first to get the ID
-        conn.execute(
-            "INSERT INTO mails (from_address, from_name, to_address, to_name, subject, html, text, date, is_read) VALUES (?, ?, ?, ?, ?, ?, ?, ?, 0)",
-            [
-                from_address.clone(),
-                from_name.to_string(),
-                to_address.clone(),
-                to_name.to_string(),
-                subject.clone(),
-                html.clone(),
-                text.clone(),
-                date.clone(),
-            ],
-        )
-        .unwrap();
-
-        let mail_id = conn.last_insert_rowid();
+            .unwrap_or_default();
+        let date = match message.date() {
+            Some(date) => date.to_rfc3339(),
+            None => {
+                parse_warnings.push("Missing or invalid Date header, using reception time".to_string());
+                received_now()
+            }
+        };
+        let headers: Vec<MailHeader> = message
+            .headers()
+            .iter()
+            .map(|header| header_of(&self.buffer, header))
+            .collect();
+
+        let mut mail = StoredMail {
+            id: 0,
+            from_address,
+            from_name,
+            to_address,
+            to_name,
+            subject,
+            html,
+            text,
+            date,
+            is_read: false,
+            parse_warnings,
+            envelope: self.envelope.clone(),
+            to: recipients_of(message.to()),
+            cc: recipients_of(message.cc()),
+            bcc: recipients_of(message.bcc()),
+            reply_to: recipients_of(message.reply_to()),
+            attachments: Vec::new(),
+        };
+
+        // Insert mail record first to get the ID, keeping the untouched bytes
+        self.storage
+            .insert_mail(&mut mail, &self.buffer, &headers)
+            .map_err(internal_error)?;
+
+        // Don't keep a half-stored mail around, the client will retry
+        if let Err(response) = self.store_attachments(&message, &mut mail) {
+            let _ = self.storage.delete_mail(mail.id);
+            return Err(response);
+        }
+
+        Ok(mail)
+    }
+
+    /// Write attachments to disk and record them against `mail`
+    fn store_attachments(&self, message: &Message, mail: &mut StoredMail) -> Result<(), Response> {
+        let mail_id = mail.id;
 
         // Process attachments using correct mail_parser API
-        let mut stored_attachments = Vec::new();
         println!("Processing {} attachments", message.attachment_count());
 
         for i in 0..message.attachment_count() {
             if let Some(attachment) = message.attachment(i as u32) {
-                // Extract filename from attachment body
-                let filename = match &attachment.body {
-                    mail_parser::PartType::Html(name) | mail_parser::PartType::Text(name) => {
-                        name.to_string()
-                    }
-                    _ => attachment
-                        .attachment_name()
-                        .unwrap_or("unnamed_attachment")
-                        .to_string(),
-                };
+                let filename = sanitize_filename(
+                    attachment.attachment_name().unwrap_or("unnamed_attachment"),
+                );
 
                 let data = attachment.contents().to_vec();
                 let headers = attachment.headers();
@@ -105,13 +310,13 @@ impl Handler for MailinHandler {
                 let content_type = headers
                     .iter()
                     .find_map(|header| {
-                        if let mail_parser::HeaderName::ContentType = header.name {
-                            if let mail_parser::HeaderValue::ContentType(ct) = &header.value {
-                                if let Some(subtype) = &ct.c_subtype {
-                                    return Some(format!("{}/{}", ct.c_type, subtype));
-                                } else {
-                                    return Some(ct.c_type.to_string());
-                                }
+                        if let mail_parser::HeaderName::ContentType = header.name
+                            && let mail_parser::HeaderValue::ContentType(ct) = &header.value
+                        {
+                            if let Some(subtype) = &ct.c_subtype {
+                                return Some(format!("{}/{}", ct.c_type, subtype));
+                            } else {
+                                return Some(ct.c_type.to_string());
                             }
                         }
                         None
@@ -123,51 +328,145 @@ impl Handler for MailinHandler {
                 // Save file to disk with unique name
                 let unique_filename = format!("{}_{}", mail_id, filename);
                 let file_url = format!("/attachments/{}", unique_filename);
-                let file_path = format!("./attachments/{}", unique_filename);
-                std::fs::write(&file_path, &data).unwrap();
-
-                conn.execute(
-                    "INSERT INTO attachments (mail_id, filename, content_type, content_disposition, size_bytes, file_url) VALUES (?, ?, ?, ?, ?, ?)",
-                    rusqlite::params![
-                        mail_id,
-                        filename.clone(),
-                        content_type.clone(),
-                        content_disposition.clone(),
-                        size_bytes,
-                        file_url.clone()
-                    ],
-                )
-                .unwrap();
-
-                let attachment_id = conn.last_insert_rowid();
-                stored_attachments.push(Attachment {
-                    id: attachment_id,
+                let file_path = self.storage.attachments_dir().join(&unique_filename);
+                std::fs::write(&file_path, &data).map_err(internal_error)?;
+
+                let mut stored_attachment = Attachment {
+                    id: 0,
                     mail_id,
                     filename,
                     content_type,
                     content_disposition,
                     size_bytes,
                     file_url,
-                });
+                };
+                self.storage
+                    .insert_attachment(&mut stored_attachment)
+                    .map_err(internal_error)?;
+                mail.attachments.push(stored_attachment);
             }
         }
 
-        let sse_mail = StoredMail {
-            id: mail_id,
-            from_address: from_address.clone(),
-            from_name: from_name.to_string(),
-            to_address: to_address.clone(),
-            to_name: to_name.to_string(),
-            subject: subject.clone(),
-            html: html.clone(),
-            text: text.clone(),
-            date: date.clone(),
-            is_read: false,
-            attachments: stored_attachments,
-        };
-        // Notify via SSE
-        let _ = self.sender.send(sse_mail);
-        OK
+        Ok(())
+    }
+}
+
+/// Answer sent when the DATA payload cannot be parsed as a message at all
+fn unparseable() -> Response {
+    Response::custom(554, "5.6.0 Message could not be parsed".to_string())
+}
+
+fn internal_error(err: impl std::fmt::Display) -> Response {
+    eprintln!("Failed to store message: {}", err);
+    INTERNAL_ERROR
+}
+
+fn address_of(addr: Option<&Addr>) -> String {
+    addr.and_then(|a| a.address.as_deref())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn name_of(addr: Option<&Addr>) -> String {
+    addr.and_then(|a| a.name.as_deref())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn recipients_of(address: Option<&Address>) -> Vec<Recipient> {
+    address
+        .map(|address| {
+            address
+                .iter()
+                .map(|addr| Recipient {
+                    name: addr.name.as_deref().unwrap_or_default().to_string(),
+                    address: addr.address.as_deref().unwrap_or_default().to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Recover the header as written on the wire along with its decoded form
+fn header_of(raw: &[u8], header: &Header) -> MailHeader {
+    let slice = |start: u32, end: u32| {
+        raw.get(start as usize..end as usize)
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default()
+    };
+    let name = slice(header.offset_field, header.offset_start)
+        .trim_end()
+        .trim_end_matches(':')
+        .trim()
+        .to_string();
+    let name = if name.is_empty() {
+        header.name.as_str().to_string()
+    } else {
+        name
+    };
+    let raw_value = slice(header.offset_start, header.offset_end)
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    let unfolded = raw_value
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let value = match &header.value {
+        HeaderValue::Text(text) => text.to_string(),
+        HeaderValue::TextList(list) => list.join(", "),
+        HeaderValue::DateTime(date) => date.to_rfc3339(),
+        HeaderValue::Address(address) => address
+            .iter()
+            .map(|addr| match (&addr.name, &addr.address) {
+                (Some(name), Some(address)) => format!("{} <{}>", name, address),
+                (None, Some(address)) => address.to_string(),
+                (Some(name), None) => name.to_string(),
+                (None, None) => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        HeaderValue::ContentType(ct) => {
+            let mut value = match &ct.c_subtype {
+                Some(subtype) => format!("{}/{}", ct.c_type, subtype),
+                None => ct.c_type.to_string(),
+            };
+            for attribute in ct.attributes.iter().flatten() {
+                value.push_str(&format!("; {}={}", attribute.name, attribute.value));
+            }
+            value
+        }
+        _ => unfolded,
+    };
+
+    MailHeader {
+        name,
+        raw_value,
+        value,
     }
 }
 
+fn received_now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    DateTime::from_timestamp(now).to_rfc3339()
+}
+
+/// Keep attachment file names on disk free of path separators
+fn sanitize_filename(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+    match cleaned.trim() {
+        "" | "." | ".." => "unnamed_attachment".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
@@ -1,27 +1,136 @@
+mod api_error;
+mod chaos;
 mod db;
+mod events;
+mod extract;
+mod html;
 mod mail_handler;
+mod openapi;
 mod models;
+mod query;
 mod rest_server;
+mod smtp_auth;
 mod smtp_server;
+mod storage;
+mod tls;
+mod webhooks;
+mod ws;
 
-use db::init_db;
+use chaos::Chaos;
+use events::EventBus;
 use rest_server::RestServer;
+use smtp_auth::SmtpAuth;
 use smtp_server::SmtpServer;
+use storage::Storage;
+use webhooks::WebhookDispatcher;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::Duration;
+
+/// Events buffered per subscriber before it is told to resync
+const EVENT_BUS_CAPACITY: usize = 100;
+/// How often attachment files without a matching row are removed
+const ORPHAN_GC_INTERVAL: Duration = Duration::from_secs(3600);
 
 #[tokio::main]
 async fn main() {
     // Init DB
     let db_path = "mails.db".to_string();
-    init_db(&db_path).unwrap();
+    let attachments_dir =
+        std::env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "./attachments".to_string());
+    let storage = match Storage::open(&db_path) {
+        Ok(storage) => storage.with_attachments_dir(attachments_dir),
+        Err(e) => {
+            eprintln!("Failed to initialize database: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     // Create attachments storage directory
-    std::fs::create_dir_all("./attachments").unwrap_or_default();
+    std::fs::create_dir_all(storage.attachments_dir()).unwrap_or_default();
+
+    // Collect attachment files left behind by deleted mails, now and then periodically
+    tokio::spawn({
+        let storage = storage.clone();
+        async move {
+            let mut interval = tokio::time::interval(ORPHAN_GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                let storage = storage.clone();
+                match tokio::task::spawn_blocking(move || storage.collect_orphaned_files()).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => println!("[GC] Removed {} orphaned attachment files", removed),
+                    Ok(Err(e)) => eprintln!("[GC] Failed to collect orphaned attachments: {}", e),
+                    Err(e) => eprintln!("[GC] Task failed: {}", e),
+                }
+            }
+        }
+    });
+
+    // Webhook targets from the configuration, more can be added through the API
+    if let Ok(urls) = std::env::var("WEBHOOK_URLS") {
+        let secret = std::env::var("WEBHOOK_SECRET").ok();
+        if let Err(e) = webhooks::register_configured(&storage, &urls, secret.as_deref()) {
+            eprintln!("[Webhook] Failed to register configured webhooks: {}", e);
+        }
+    }
+
+    let events = EventBus::new(storage.clone(), EVENT_BUS_CAPACITY);
+    let max_attempts = std::env::var("WEBHOOK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(webhooks::MAX_ATTEMPTS);
+    let retry_delay = std::env::var("WEBHOOK_RETRY_DELAY")
+        .ok()
+        .and_then(|value| query::parse_duration(&value).ok())
+        .unwrap_or(webhooks::INITIAL_BACKOFF);
+    let dispatcher = WebhookDispatcher::new(storage.clone()).with_retries(max_attempts, retry_delay);
+    tokio::spawn(dispatcher.run(events.subscribe()));
+
+    // SMTP AUTH, off unless credentials or SMTP_AUTH are configured: once offered,
+    // clients that do not authenticate cannot send mail
+    let smtp_auth = match std::env::var("SMTP_AUTH_USERS") {
+        Ok(users) => SmtpAuth::users(&users).unwrap_or_else(|e| {
+            eprintln!("Invalid SMTP_AUTH_USERS: {}", e);
+            std::process::exit(1);
+        }),
+        Err(_) => match std::env::var("SMTP_AUTH").as_deref() {
+            Ok("1" | "true" | "on") => SmtpAuth::AnyCredentials,
+            _ => SmtpAuth::Disabled,
+        },
+    };
+    // Failure injection rules, managed through the API and applied by the SMTP handler
+    let chaos = Arc::new(Chaos::default());
+    let smtp_server = SmtpServer::new(storage.clone(), events.clone())
+        .with_auth(smtp_auth)
+        .with_chaos(Arc::clone(&chaos));
 
-    let (sender, _) = broadcast::channel(100);
-    let smtp_server = SmtpServer::new(db_path.clone(), sender.clone());
-    let rest_server = Arc::new(RestServer::new(db_path.clone(), sender.clone()));
+    // STARTTLS and SMTPS with the configured certificate, or a self-signed one generated on first start
+    let starttls = !matches!(std::env::var("SMTP_TLS").as_deref(), Ok("0" | "false" | "off"));
+    let smtp_server = smtp_server.with_starttls(starttls);
+    let smtp_server = if starttls || std::env::var("SMTPS_PORT").is_ok() {
+        let config = match (std::env::var("SMTP_TLS_CERT"), std::env::var("SMTP_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => tls::server_config(Path::new(&cert), Path::new(&key)),
+            (Err(_), Err(_)) => {
+                let dir = std::env::var("SMTP_TLS_DIR").unwrap_or_else(|_| "./tls".to_string());
+                tls::self_signed_config(Path::new(&dir))
+            }
+            _ => {
+                eprintln!("SMTP_TLS_CERT and SMTP_TLS_KEY must be set together");
+                std::process::exit(1);
+            }
+        };
+        match config {
+            Ok(config) => smtp_server.with_tls(config),
+            Err(e) => {
+                eprintln!("Failed to set up TLS: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        smtp_server
+    };
+    let rest_server = Arc::new(RestServer::new(storage, events).with_chaos(chaos));
     let smtp_fut = smtp_server.run();
     let rest_fut = rest_server.run();
     let _ = tokio::join!(smtp_fut, rest_fut);
@@ -30,6 +139,8 @@ async fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use db::init_db;
+    use mailin::Handler;
     use rusqlite::Connection;
     use tempfile::TempDir;
 
@@ -53,7 +164,7 @@ mod tests {
             .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name IN ('mails', 'attachments')")
             .unwrap();
         let tables: Vec<String> = stmt
-            .query_map([], |row| Ok(row.get(0)?))
+            .query_map([], |row| row.get(0))
             .unwrap()
             .map(|r| r.unwrap())
             .collect();
@@ -256,4 +367,1529 @@ mod tests {
         assert_eq!(mails[0].1, "Subject 2");
         assert_eq!(mails[1].1, "Subject 1");
     }
+
+    #[test]
+    fn test_data_end_stores_raw_message() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let mut handler = mail_handler::MailinHandler::new(storage.clone(), EventBus::new(storage, 10));
+
+        let raw = b"From: Sender <sender@example.com>\r\n\
+To: recipient@example.com\r\n\
+Subject: Raw\r\n\
+Date: Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Caf=C3=A9\r\n";
+
+        handler.data_start("client.example.com", "sender@example.com", false, &[]);
+        handler.data(raw).unwrap();
+        handler.data_end();
+
+        let conn = Connection::open(&db_path).unwrap();
+        let stored: Vec<u8> = conn
+            .query_row("SELECT raw FROM mails", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, raw.to_vec());
+    }
+
+    #[test]
+    fn test_data_end_accepts_minimal_message() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let mut handler = mail_handler::MailinHandler::new(storage.clone(), EventBus::new(storage, 10));
+
+        handler.data_start("client.example.com", "sender@example.com", false, &[]);
+        handler.data(b"Bcc: hidden@example.com\r\n\r\nNo subject, no date\r\n").unwrap();
+        let response = handler.data_end();
+        assert_eq!(response.code, 250);
+
+        let conn = Connection::open(&db_path).unwrap();
+        let (subject, to_address, warnings): (String, String, String) = conn
+            .query_row("SELECT subject, to_address, parse_warnings FROM mails", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(subject, "");
+        assert_eq!(to_address, "");
+        let warnings: Vec<String> = serde_json::from_str(&warnings).unwrap();
+        assert!(warnings.iter().any(|w| w.contains("Subject")));
+        assert!(warnings.iter().any(|w| w.contains("Date")));
+    }
+
+    #[test]
+    fn test_data_end_rejects_empty_message() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let refusal = mail_handler::Refusal::default();
+        let mut handler = mail_handler::MailinHandler::new(storage.clone(), EventBus::new(storage, 10))
+            .with_refusal(Arc::clone(&refusal));
+
+        handler.data_start("client.example.com", "sender@example.com", false, &[]);
+        // Accepted to end the transaction, refused through `refusal`
+        assert_eq!(handler.data_end().code, 250);
+        assert_eq!(refusal.lock().unwrap().take().map(|response| response.code), Some(554));
+    }
+
+    #[test]
+    fn test_session_goes_on_after_a_refused_message() {
+        use std::io::BufReader;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), EventBus::new(storage.clone(), 10));
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut smtp = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(send(&mut smtp, "").starts_with("220"));
+        assert!(send(&mut smtp, "EHLO client.test").starts_with("250"));
+        assert!(send(&mut smtp, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert!(send(&mut smtp, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut smtp, "DATA").starts_with("354"));
+        assert!(send(&mut smtp, ".").starts_with("554"));
+        assert!(send(&mut smtp, "RSET").starts_with("250"));
+        assert!(send(&mut smtp, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert!(send(&mut smtp, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut smtp, "DATA").starts_with("354"));
+        assert!(send(&mut smtp, "Subject: Second try\r\n\r\nHi\r\n.").starts_with("250"));
+        assert!(send(&mut smtp, "QUIT").starts_with("221"));
+    }
+
+    #[test]
+    fn test_data_end_records_envelope() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let mut receiver = events.subscribe();
+        let mut handler = mail_handler::MailinHandler::new(storage, events);
+
+        let recipients = vec![
+            "visible@example.com".to_string(),
+            "hidden@example.com".to_string(),
+        ];
+        handler.data_start("client.example.com", "bounce+123@example.com", true, &recipients);
+        handler
+            .data(b"From: app@example.com\r\nTo: visible@example.com\r\nSubject: Hi\r\n\r\nBody\r\n")
+            .unwrap();
+        assert_eq!(handler.data_end().code, 250);
+
+        let models::MailEvent::Created(mail) = receiver.try_recv().unwrap().event else {
+            panic!("expected a mail.created event");
+        };
+        assert_eq!(mail.envelope.rcpt_to, recipients);
+
+        let conn = Connection::open(&db_path).unwrap();
+        let (helo, from, to, is_8bit): (String, String, String, i64) = conn
+            .query_row(
+                "SELECT helo_domain, envelope_from, envelope_to, is_8bit FROM mails",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(helo, "client.example.com");
+        assert_eq!(from, "bounce+123@example.com");
+        assert_eq!(serde_json::from_str::<Vec<String>>(&to).unwrap(), recipients);
+        assert_eq!(is_8bit, 1);
+    }
+
+    #[test]
+    fn test_data_end_stores_all_recipients() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let mut receiver = events.subscribe();
+        let mut handler = mail_handler::MailinHandler::new(storage, events);
+
+        handler.data_start("client.example.com", "app@example.com", false, &[]);
+        handler
+            .data(
+                b"From: app@example.com\r\n\
+To: Alice <alice@example.com>, bob@example.com\r\n\
+Cc: Carol <carol@example.com>\r\n\
+Reply-To: support@example.com\r\n\
+Subject: Fan out\r\n\r\nBody\r\n",
+            )
+            .unwrap();
+        assert_eq!(handler.data_end().code, 250);
+
+        let models::MailEvent::Created(mail) = receiver.try_recv().unwrap().event else {
+            panic!("expected a mail.created event");
+        };
+        assert_eq!(mail.to.len(), 2);
+        assert_eq!(mail.to[0].name, "Alice");
+        assert_eq!(mail.to[1].address, "bob@example.com");
+        assert_eq!(mail.cc[0].address, "carol@example.com");
+        assert!(mail.bcc.is_empty());
+        assert_eq!(mail.reply_to[0].address, "support@example.com");
+
+        let conn = Connection::open(&db_path).unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM recipients WHERE mail_id = ?",
+                [mail.id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_data_end_stores_ordered_headers() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let mut handler = mail_handler::MailinHandler::new(storage.clone(), EventBus::new(storage, 10));
+
+        handler.data_start("client.example.com", "app@example.com", false, &[]);
+        handler
+            .data(
+                b"From: app@example.com\r\n\
+To: user@example.com\r\n\
+Subject: =?UTF-8?B?Q2Fmw6k=?=\r\n\
+List-Unsubscribe: <https://example.com/unsub>,\r\n\
+\x20<mailto:unsub@example.com>\r\n\
+X-Tracking-Id: abc123\r\n\r\nBody\r\n",
+            )
+            .unwrap();
+        assert_eq!(handler.data_end().code, 250);
+
+        let conn = Connection::open(&db_path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name, raw_value, value FROM headers ORDER BY position")
+            .unwrap();
+        let headers: Vec<(String, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+
+        let names: Vec<&str> = headers.iter().map(|h| h.0.as_str()).collect();
+        assert_eq!(names, ["From", "To", "Subject", "List-Unsubscribe", "X-Tracking-Id"]);
+        assert_eq!(headers[2].1.trim(), "=?UTF-8?B?Q2Fmw6k=?=");
+        assert_eq!(headers[2].2, "Café");
+        assert!(headers[3].1.contains("\r\n <mailto:"));
+        assert_eq!(headers[4].2, "abc123");
+    }
+
+    #[test]
+    fn test_migrations_set_schema_version() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let conn = Connection::open(&db_path).unwrap();
+
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, db::SCHEMA_VERSION);
+
+        // Running again is a no-op
+        init_db(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_migrations_upgrade_legacy_database() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("legacy.db");
+        let db_path = db_path.to_str().unwrap();
+
+        // Schema as created by releases without versioning
+        let conn = Connection::open(db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE mails (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                from_address TEXT, from_name TEXT, to_address TEXT, to_name TEXT,
+                subject TEXT, html TEXT, text TEXT, date TEXT, is_read INTEGER DEFAULT 0
+            );
+            CREATE TABLE attachments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                mail_id INTEGER NOT NULL, filename TEXT NOT NULL, content_type TEXT NOT NULL,
+                content_disposition TEXT, size_bytes INTEGER NOT NULL, file_url TEXT NOT NULL,
+                FOREIGN KEY(mail_id) REFERENCES mails(id) ON DELETE CASCADE
+            );
+            INSERT INTO mails (from_address, to_address, to_name, subject, date)
+            VALUES ('old@example.com', 'alice@example.com', 'Alice', 'Old', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+        drop(conn);
+
+        init_db(db_path).unwrap();
+
+        let conn = Connection::open(db_path).unwrap();
+        let (subject, raw): (String, Option<Vec<u8>>) = conn
+            .query_row("SELECT subject, raw FROM mails", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(subject, "Old");
+        assert!(raw.is_none());
+        // Found by recipient like mails received since
+        let recipient: (String, String, String) = conn
+            .query_row("SELECT kind, name, address FROM recipients", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(recipient, ("to".to_string(), "Alice".to_string(), "alice@example.com".to_string()));
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, db::SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_migrations_keep_columns_of_unversioned_builds() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("unversioned.db");
+        let db_path = db_path.to_str().unwrap();
+
+        // Builds from before versioning added columns to `CREATE TABLE` itself
+        let conn = Connection::open(db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE mails (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                from_address TEXT, from_name TEXT, to_address TEXT, to_name TEXT,
+                subject TEXT, html TEXT, text TEXT, date TEXT, is_read INTEGER DEFAULT 0,
+                raw BLOB, parse_warnings TEXT,
+                helo_domain TEXT, envelope_from TEXT, envelope_to TEXT, is_8bit INTEGER DEFAULT 0
+            );
+            CREATE TABLE recipients (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                mail_id INTEGER NOT NULL, kind TEXT NOT NULL, name TEXT NOT NULL, address TEXT NOT NULL
+            );
+            CREATE TABLE headers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                mail_id INTEGER NOT NULL, position INTEGER NOT NULL,
+                name TEXT NOT NULL, raw_value TEXT NOT NULL, value TEXT NOT NULL
+            );
+            INSERT INTO mails (subject, raw, helo_domain) VALUES ('Kept', X'6869', 'client.test');
+            INSERT INTO headers (mail_id, position, name, raw_value, value) VALUES (1, 0, 'Subject', 'Kept', 'Kept');",
+        )
+        .unwrap();
+        drop(conn);
+
+        init_db(db_path).unwrap();
+
+        let conn = Connection::open(db_path).unwrap();
+        let (raw, helo): (Vec<u8>, String) = conn
+            .query_row("SELECT raw, helo_domain FROM mails", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((raw.as_slice(), helo.as_str()), (b"hi".as_slice(), "client.test"));
+        let headers: i64 = conn.query_row("SELECT COUNT(*) FROM headers", [], |row| row.get(0)).unwrap();
+        assert_eq!(headers, 1);
+    }
+
+    #[test]
+    fn test_migrations_refuse_newer_schema() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let conn = Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "user_version", db::SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+
+        assert!(matches!(
+            init_db(&db_path),
+            Err(db::DbError::SchemaTooNew { .. })
+        ));
+    }
+
+    fn sample_mail(subject: &str, to: &str) -> models::StoredMail {
+        models::StoredMail {
+            id: 0,
+            from_address: "app@example.com".to_string(),
+            from_name: String::new(),
+            to_address: to.to_string(),
+            to_name: String::new(),
+            subject: subject.to_string(),
+            html: String::new(),
+            text: String::new(),
+            date: "2024-01-01T00:00:00Z".to_string(),
+            is_read: false,
+            parse_warnings: Vec::new(),
+            envelope: models::Envelope::default(),
+            to: vec![models::Recipient {
+                name: String::new(),
+                address: to.to_string(),
+            }],
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            reply_to: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_storage_uses_wal_and_filters_by_recipient() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        let journal_mode: String = storage
+            .conn()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        storage
+            .insert_mail(&mut sample_mail("For Alice", "alice@example.com"), b"", &[])
+            .unwrap();
+        storage
+            .insert_mail(&mut sample_mail("For Bob", "bob@example.com"), b"", &[])
+            .unwrap();
+
+        let filter = storage::MailFilter {
+            recipient: Some("ALICE@example.com".to_string()),
+            ..Default::default()
+        };
+        let page = storage.list_summaries(&filter, 10, 0).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].subject, "For Alice");
+        assert_eq!(page.items[0].to_address, "alice@example.com");
+
+        // A kind alone matches mails with any recipient of that kind
+        let mut copied = sample_mail("Copied", "carol@example.com");
+        copied.cc.push(models::Recipient {
+            name: String::new(),
+            address: "dave@example.com".to_string(),
+        });
+        storage.insert_mail(&mut copied, b"", &[]).unwrap();
+        let filter = storage::MailFilter {
+            kind: Some("cc".to_string()),
+            ..Default::default()
+        };
+        let page = storage.list_summaries(&filter, 10, 0).unwrap();
+        assert_eq!((page.total, page.items[0].subject.as_str()), (1, "Copied"));
+    }
+
+    #[test]
+    fn test_storage_handles_concurrent_writers() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|n| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let mut mail = sample_mail(&format!("{}-{}", n, i), "load@example.com");
+                        storage.insert_mail(&mut mail, b"", &[]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let page = storage
+            .list_summaries(&storage::MailFilter::default(), 10, 0)
+            .unwrap();
+        assert_eq!(page.total, 400);
+    }
+
+    #[test]
+    fn test_storage_delete_removes_attachment_rows_and_files() {
+        let (temp_dir, db_path) = setup_test_db();
+        let attachments_dir = temp_dir.path().join("attachments");
+        std::fs::create_dir_all(&attachments_dir).unwrap();
+        let storage = Storage::open(&db_path)
+            .unwrap()
+            .with_attachments_dir(&attachments_dir);
+        let events = EventBus::new(storage.clone(), 10);
+        let mut receiver = events.subscribe();
+        let mut handler = mail_handler::MailinHandler::new(storage.clone(), events);
+
+        handler.data_start("client.example.com", "app@example.com", false, &[]);
+        handler
+            .data(
+                b"From: app@example.com\r\n\
+To: user@example.com\r\n\
+Subject: With attachment\r\n\
+Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nBody\r\n\
+--b\r\nContent-Type: application/json\r\n\
+Content-Disposition: attachment; filename=\"data.json\"\r\n\r\n{}\r\n\
+--b--\r\n",
+            )
+            .unwrap();
+        assert_eq!(handler.data_end().code, 250);
+        let models::MailEvent::Created(mail) = receiver.try_recv().unwrap().event else {
+            panic!("expected a mail.created event");
+        };
+        assert_eq!(mail.attachments.len(), 1);
+        let file_path = attachments_dir.join(format!("{}_data.json", mail.id));
+        assert!(file_path.exists());
+
+        assert!(storage.delete_mail(mail.id).unwrap());
+        assert!(!storage.delete_mail(mail.id).unwrap());
+
+        let count: i64 = storage
+            .conn()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM attachments", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        assert!(!file_path.exists());
+    }
+
+    #[test]
+    fn test_storage_collects_orphaned_files() {
+        let (temp_dir, db_path) = setup_test_db();
+        let attachments_dir = temp_dir.path().join("attachments");
+        std::fs::create_dir_all(&attachments_dir).unwrap();
+        let storage = Storage::open(&db_path)
+            .unwrap()
+            .with_attachments_dir(&attachments_dir);
+
+        let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+        let orphan = attachments_dir.join("42_orphan.txt");
+        std::fs::write(&orphan, "orphan").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&orphan)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let fresh = attachments_dir.join("43_in_flight.txt");
+        std::fs::write(&fresh, "in flight").unwrap();
+
+        assert_eq!(storage.collect_orphaned_files().unwrap(), 1);
+        assert!(!orphan.exists());
+        assert!(fresh.exists());
+    }
+
+    #[test]
+    fn test_storage_paginates_summaries() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        for i in 0..5 {
+            let mut mail = sample_mail(&format!("Mail {}", i), "user@example.com");
+            mail.date = format!("2024-01-0{}T00:00:00Z", i + 1);
+            mail.text = "x".repeat(1000);
+            storage.insert_mail(&mut mail, b"", &[]).unwrap();
+        }
+        storage.set_read(1, true).unwrap();
+
+        let filter = storage::MailFilter::default();
+        let page = storage.list_summaries(&filter, 2, 0).unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.unread, 4);
+        let subjects: Vec<&str> = page.items.iter().map(|m| m.subject.as_str()).collect();
+        assert_eq!(subjects, ["Mail 4", "Mail 3"]);
+        assert_eq!(page.items[0].preview.len(), 200);
+        assert_eq!(page.items[0].attachment_count, 0);
+
+        let last = storage.list_summaries(&filter, 2, 4).unwrap();
+        assert_eq!(last.items.len(), 1);
+        assert_eq!(last.items[0].subject, "Mail 0");
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let filter =
+            query::parse(r#"from:alice to:bob@example.com subject:"reset password" is:unread has:attachment after:2024-01-01 welcome"#)
+                .unwrap();
+        assert_eq!(filter.from, ["alice"]);
+        assert_eq!(filter.to, ["bob@example.com"]);
+        assert_eq!(filter.subject, ["reset password"]);
+        assert_eq!(filter.is_read, Some(false));
+        assert_eq!(filter.has_attachment, Some(true));
+        assert_eq!(filter.after.as_deref(), Some("2024-01-01"));
+        assert_eq!(filter.text, ["welcome"]);
+
+        assert!(query::parse("is:starred").is_err());
+        assert!(query::parse("before:yesterday").is_err());
+        assert!(query::parse("from:").is_err());
+        // Unknown prefixes are plain text
+        assert_eq!(query::parse("https://example.com").unwrap().text, ["https://example.com"]);
+    }
+
+    #[test]
+    fn test_storage_filters_with_query() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        let mut signup = sample_mail("Welcome aboard", "alice@example.com");
+        signup.date = "2024-03-01T10:00:00+02:00".to_string();
+        storage.insert_mail(&mut signup, b"", &[]).unwrap();
+        let mut reset = sample_mail("Reset your password", "bob@example.com");
+        reset.date = "2024-02-01T00:00:00Z".to_string();
+        storage.insert_mail(&mut reset, b"", &[]).unwrap();
+        storage.set_read(reset.id, true).unwrap();
+
+        let subjects = |q: &str| -> Vec<String> {
+            storage
+                .list_summaries(&query::parse(q).unwrap(), 10, 0)
+                .unwrap()
+                .items
+                .into_iter()
+                .map(|m| m.subject)
+                .collect()
+        };
+        assert_eq!(subjects("to:alice"), ["Welcome aboard"]);
+        assert_eq!(subjects("subject:PASSWORD"), ["Reset your password"]);
+        assert_eq!(subjects("is:unread"), ["Welcome aboard"]);
+        assert_eq!(subjects("is:read to:alice"), Vec::<String>::new());
+        assert_eq!(subjects("after:2024-02-15"), ["Welcome aboard"]);
+        assert_eq!(subjects("before:2024-02-15 from:app"), ["Reset your password"]);
+        assert_eq!(subjects("has:attachment"), Vec::<String>::new());
+        assert_eq!(subjects("welcome"), ["Welcome aboard"]);
+        assert_eq!(subjects("subject:100%"), Vec::<String>::new());
+        // Reply-To names who answers, not who received the mail
+        let mut support = sample_mail("Your ticket", "carol@example.com");
+        support.reply_to = vec![models::Recipient { name: String::new(), address: "helpdesk@example.com".to_string() }];
+        storage.insert_mail(&mut support, b"", &[]).unwrap();
+        assert_eq!(subjects("to:carol"), ["Your ticket"]);
+        assert_eq!(subjects("to:helpdesk"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_strip_tags() {
+        assert_eq!(
+            html::strip_tags(
+                "<html><head><style>p { color: red }</style></head><body><p>Hello&nbsp;<b>W&amp;rld</b></p><br/>Bye &#8212; &unknown;<script>alert(1)</script></body></html>"
+            ),
+            "Hello W&rld Bye — &unknown;"
+        );
+    }
+
+    #[test]
+    fn test_storage_full_text_search() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        let mut code = sample_mail("Your account", "alice@example.com");
+        code.html = "<p>Your verification <b>code</b> is <span class=\"otp\">493021</span></p>".to_string();
+        storage.insert_mail(&mut code, b"", &[]).unwrap();
+        let mut invoice = sample_mail("Invoice", "bob@example.com");
+        invoice.text = "Please find your invoice attached. Invoice total: 42 EUR".to_string();
+        storage.insert_mail(&mut invoice, b"", &[]).unwrap();
+        storage
+            .insert_attachment(&mut models::Attachment {
+                id: 0,
+                mail_id: invoice.id,
+                filename: "invoice-2024.pdf".to_string(),
+                content_type: "application/pdf".to_string(),
+                content_disposition: None,
+                size_bytes: 0,
+                file_url: "/attachments/x".to_string(),
+            })
+            .unwrap();
+
+        let search = |q: &str| storage.list_summaries(&query::parse(q).unwrap(), 10, 0).unwrap();
+
+        let page = search("verification");
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].id, code.id);
+        let snippet = page.items[0].snippet.as_deref().unwrap();
+        assert!(snippet.contains("<mark>verification</mark>"), "{}", snippet);
+
+        // Markup is not indexed, only visible text
+        assert_eq!(search("span").total, 0);
+        assert_eq!(search("493021").total, 1);
+        assert_eq!(search("invoice-2024").items[0].id, invoice.id);
+        assert_eq!(search("invo to:bob").total, 1);
+        assert_eq!(search("invo to:alice").total, 0);
+        assert!(storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap().items[0].snippet.is_none());
+
+        storage.delete_mail(invoice.id).unwrap();
+        assert_eq!(search("invoice").total, 0);
+    }
+
+    #[test]
+    fn test_query_parse_duration() {
+        assert_eq!(query::parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(query::parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(query::parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(query::parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(query::parse_duration("soon").is_err());
+        assert!(query::parse_duration("10h").is_err());
+    }
+
+    #[test]
+    fn test_storage_filters_after_id() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+
+        let mut old = sample_mail("Welcome", "user@example.com");
+        storage.insert_mail(&mut old, b"", &[]).unwrap();
+        let filter = storage::MailFilter {
+            subject: vec!["welcome".to_string()],
+            after_id: Some(old.id),
+            ..Default::default()
+        };
+        assert_eq!(storage.list_summaries(&filter, 1, 0).unwrap().total, 0);
+
+        let mut new = sample_mail("Welcome again", "user@example.com");
+        storage.insert_mail(&mut new, b"", &[]).unwrap();
+        let page = storage.list_summaries(&filter, 1, 0).unwrap();
+        assert_eq!(page.items[0].id, new.id);
+
+        // Waiting answers the next mail to arrive, whatever its Date header says
+        let mut later = sample_mail("Welcome back", "user@example.com");
+        later.date = "2030-01-01T00:00:00Z".to_string();
+        storage.insert_mail(&mut later, b"", &[]).unwrap();
+        assert_eq!(storage.first_matching(&filter).unwrap().unwrap().id, new.id);
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let mut mail = sample_mail("Confirm your email", "alice@example.com");
+        mail.html = "<p><a class=\"btn\" HREF='https://app.example.com/confirm?token=abc&amp;u=1'>Confirm <b>email</b></a> \
+            <abbr>x</abbr><a name=\"top\">no link</a><a href=https://example.com/help>Help</a>\
+            <a href=\"https://example.com/help\">Help again</a></p>"
+            .to_string();
+        mail.text = "Confirm at https://app.example.com/confirm?token=abc&u=1.\n\
+            (or see https://example.com/faq) and http://example.com/faq, again: https://example.com/faq"
+            .to_string();
+
+        let links: Vec<(String, String, String)> = extract::links(&mail)
+            .into_iter()
+            .map(|link| (link.url, link.text, link.source))
+            .collect();
+        let link = |url: &str, text: &str, source: &str| (url.to_string(), text.to_string(), source.to_string());
+        assert_eq!(
+            links,
+            [
+                link("https://app.example.com/confirm?token=abc&u=1", "Confirm email", "html"),
+                link("https://example.com/help", "Help", "html"),
+                link("https://app.example.com/confirm?token=abc&u=1", "", "text"),
+                link("https://example.com/faq", "", "text"),
+                link("http://example.com/faq", "", "text"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_codes() {
+        let mut mail = sample_mail("482913 is your login code", "alice@example.com");
+        mail.text = "Use 482913 or backup code ABC-7781. Order 12, valid until 2024-01-31.".to_string();
+        mail.html = "<p>Code: <b>551002</b></p><p>Ref A12345</p>".to_string();
+
+        let digits = |min, max| extract::codes(&mail, &extract::CodePattern::Digits { min, max });
+        assert_eq!(digits(4, 8), ["482913", "7781", "2024", "551002"]);
+        assert_eq!(digits(6, 6), ["482913", "551002"]);
+
+        let pattern = extract::CodePattern::Regex(regex::Regex::new(r"backup code ([A-Z]+-\d+)").unwrap());
+        assert_eq!(extract::codes(&mail, &pattern), ["ABC-7781"]);
+        let pattern = extract::CodePattern::Regex(regex::Regex::new(r"A\d{5}").unwrap());
+        assert_eq!(extract::codes(&mail, &pattern), ["A12345"]);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retries_signs_and_logs_deliveries() {
+        use axum::{Router, http::HeaderMap, http::StatusCode, routing::post};
+        use std::sync::Mutex;
+
+        // Stub target failing the first request, then recording what it receives
+        type Requests = Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>;
+        let received: Requests = Arc::default();
+        let app = Router::new().route("/hook", post({
+            let received = Arc::clone(&received);
+            move |headers: HeaderMap, body: axum::body::Bytes| {
+                let received = Arc::clone(&received);
+                async move {
+                    let mut received = received.lock().unwrap();
+                    received.push((headers, body.to_vec()));
+                    if received.len() == 1 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::NO_CONTENT }
+                }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        // RFC 4231 test case 2
+        assert_eq!(
+            webhooks::sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let webhook = storage
+            .insert_webhook(&url, Some("s3cret"), &["mail.deleted".to_string(), "inbox.cleared".to_string()])
+            .unwrap();
+        storage.insert_webhook("http://127.0.0.1:9/unused", None, &["mail.created".to_string()]).unwrap();
+        webhooks::register_configured(&storage, &format!("{}, ", url), None).unwrap();
+        assert_eq!(storage.list_webhooks().unwrap().len(), 2);
+
+        let events = EventBus::new(storage.clone(), 10);
+        let dispatcher = WebhookDispatcher::new(storage.clone()).with_retries(3, Duration::from_millis(10));
+        tokio::spawn(dispatcher.run(events.subscribe()));
+        let mut mail = sample_mail("Bye", "alice@example.com");
+        mail.id = 7;
+        events.publish(models::MailEvent::Deleted(mail.clone())).unwrap();
+
+        let mut deliveries = Vec::new();
+        for _ in 0..200 {
+            deliveries = storage.list_deliveries(Some(webhook.id), 10).unwrap();
+            if deliveries.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let attempts: Vec<_> = deliveries.iter().map(|d| (d.attempt, d.status, d.event.as_str(), d.mail_id)).collect();
+        assert_eq!(attempts, [(2, Some(204), "mail.deleted", Some(7)), (1, Some(503), "mail.deleted", Some(7))]);
+        assert!(deliveries[1].error.is_some() && deliveries[0].error.is_none());
+
+        // Clearing the inbox concerns no mail in particular
+        events.publish(models::MailEvent::Cleared).unwrap();
+        for _ in 0..200 {
+            deliveries = storage.list_deliveries(Some(webhook.id), 10).unwrap();
+            if deliveries.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!((deliveries[0].event.as_str(), deliveries[0].mail_id), ("inbox.cleared", None));
+
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[1];
+        assert_eq!(headers["x-webhook-event"], "mail.deleted");
+        assert_eq!(headers["x-webhook-attempt"], "2");
+        assert_eq!(headers["x-webhook-signature"], webhooks::sign("s3cret", body).as_str());
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["id"], 7);
+        assert_eq!(payload["subject"], "Bye");
+        let (headers, body) = &received[2];
+        assert_eq!(headers["x-webhook-event"], "inbox.cleared");
+        assert_eq!(body.as_slice(), b"{}");
+    }
+
+    #[test]
+    fn test_event_bus_logs_and_replays_events() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let mut receiver = events.subscribe();
+
+        let mut kept = sample_mail("Kept", "alice@example.com");
+        storage.insert_mail(&mut kept, b"", &[]).unwrap();
+        let mut gone = sample_mail("Gone", "bob@example.com");
+        storage.insert_mail(&mut gone, b"", &[]).unwrap();
+        let created = events.publish(models::MailEvent::Created(kept.clone())).unwrap();
+        events.publish(models::MailEvent::Created(gone.clone())).unwrap();
+        storage.set_read(kept.id, true).unwrap();
+        let updated = events
+            .publish(models::MailEvent::Updated(models::StoredMail { is_read: true, ..kept.clone() }))
+            .unwrap();
+        storage.delete_mail(gone.id).unwrap();
+        events.publish(models::MailEvent::Deleted(gone.clone())).unwrap();
+
+        let live: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|published| (published.id, published.to_wire().event))
+            .collect();
+        assert_eq!(
+            live,
+            [(created, "mail.created"), (created + 1, "mail.created"), (updated, "mail.updated"), (updated + 1, "mail.deleted")]
+        );
+
+        // Replay reflects the current state, skipping mails deleted since
+        let replayed = events.replay(0).unwrap().unwrap();
+        let summary: Vec<_> = replayed.iter().map(|event| (event.event, event.data["id"].clone())).collect();
+        assert_eq!(
+            summary,
+            [
+                ("mail.created", kept.id.into()),
+                ("mail.updated", kept.id.into()),
+                ("mail.deleted", gone.id.into()),
+            ]
+        );
+        assert_eq!(replayed[0].data["is_read"], true);
+        assert_eq!(events.replay(updated).unwrap().unwrap().len(), 1);
+        assert!(events.replay(updated + 1).unwrap().unwrap().is_empty());
+
+        // Ids this database never handed out, or pruned ones, cannot be replayed
+        assert!(events.replay(updated + 2).unwrap().is_none());
+        storage.conn().unwrap().execute("DELETE FROM events WHERE id <= ?", [created + 1]).unwrap();
+        assert!(events.replay(created).unwrap().is_none());
+        assert_eq!(events.replay(created + 1).unwrap().unwrap().len(), 2);
+
+        // A bulk change is one event carrying the ids, the log cannot rebuild it
+        let bulk = events
+            .publish(models::MailEvent::UpdatedMany { ids: vec![kept.id], is_read: false })
+            .unwrap();
+        let wire = receiver.try_recv().unwrap().to_wire();
+        assert_eq!((wire.id, wire.event), (bulk, "mails.updated"));
+        assert_eq!(wire.data, serde_json::json!({ "ids": [kept.id], "is_read": false }));
+        assert!(receiver.try_recv().is_err());
+        assert!(events.replay(created + 1).unwrap().is_none());
+        assert!(events.replay(bulk).unwrap().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_filters_events_and_runs_commands() {
+        use axum::extract::{Query, WebSocketUpgrade};
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let app = axum::Router::new().route("/ws", axum::routing::get({
+            let (storage, events) = (storage.clone(), events.clone());
+            move |upgrade: WebSocketUpgrade, Query(params): Query<ws::SubscribeParams>| {
+                let (storage, events) = (storage.clone(), events.clone());
+                async move { upgrade.on_upgrade(move |socket| ws::serve(socket, storage, events, params)) }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws?recipient=ALICE@example.com", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        async fn next<S>(socket: &mut S) -> serde_json::Value
+        where
+            S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+        {
+            loop {
+                if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                    return serde_json::from_str(&text).unwrap();
+                }
+            }
+        }
+        let command = |json: serde_json::Value| Message::Text(json.to_string());
+
+        // Events for other recipients are filtered out
+        let mut for_bob = sample_mail("For Bob", "bob@example.com");
+        storage.insert_mail(&mut for_bob, b"", &[]).unwrap();
+        events.publish(models::MailEvent::Created(for_bob.clone())).unwrap();
+        let mut for_alice = sample_mail("Your code", "alice@example.com");
+        storage.insert_mail(&mut for_alice, b"", &[]).unwrap();
+        let created = events.publish(models::MailEvent::Created(for_alice.clone())).unwrap();
+        let event = next(&mut socket).await;
+        assert_eq!((event["type"].as_str(), event["event"].as_str()), (Some("event"), Some("mail.created")));
+        assert_eq!((event["id"].as_i64(), event["data"]["id"].as_i64()), (Some(created), Some(for_alice.id)));
+
+        // Commands are answered, their effects broadcast like any other change
+        socket.send(command(serde_json::json!({ "type": "mark_read", "id": for_alice.id, "request_id": "1" }))).await.unwrap();
+        assert_eq!(next(&mut socket).await, serde_json::json!({ "type": "result", "request_id": "1" }));
+        let updated = next(&mut socket).await;
+        assert_eq!((updated["event"].as_str(), updated["data"]["is_read"].as_bool()), (Some("mail.updated"), Some(true)));
+        assert!(storage.get_mail(for_alice.id).unwrap().unwrap().is_read);
+
+        socket.send(command(serde_json::json!({ "type": "subscribe", "subject": "(", "request_id": "2" }))).await.unwrap();
+        let error = next(&mut socket).await;
+        assert_eq!((error["type"].as_str(), error["request_id"].as_str()), (Some("error"), Some("2")));
+        socket.send(command(serde_json::json!({ "type": "delete", "id": 999 }))).await.unwrap();
+        assert_eq!(next(&mut socket).await["message"], "Mail 999 not found");
+        socket.send(command(serde_json::json!({ "type": "explode" }))).await.unwrap();
+        assert_eq!(next(&mut socket).await["type"], "error");
+
+        // Filters can be replaced, here to follow Bob's mails instead
+        socket.send(command(serde_json::json!({ "type": "subscribe", "recipient": "bob@example.com", "subject": "^for" }))).await.unwrap();
+        assert_eq!(next(&mut socket).await["type"], "result");
+        socket.send(command(serde_json::json!({ "type": "delete", "id": for_bob.id }))).await.unwrap();
+        assert_eq!(next(&mut socket).await["type"], "result");
+        let deleted = next(&mut socket).await;
+        assert_eq!((deleted["event"].as_str(), deleted["data"]["id"].as_i64()), (Some("mail.deleted"), Some(for_bob.id)));
+        assert!(storage.get_mail(for_bob.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_storage_bulk_operations() {
+        let (temp_dir, db_path) = setup_test_db();
+        let attachments_dir = temp_dir.path().join("attachments");
+        std::fs::create_dir_all(&attachments_dir).unwrap();
+        let storage = Storage::open(&db_path)
+            .unwrap()
+            .with_attachments_dir(&attachments_dir);
+        let mut ids = Vec::new();
+        for (subject, to) in [
+            ("Welcome", "alice@example.com"),
+            ("Reset your password", "alice@example.com"),
+            ("Welcome", "bob@example.com"),
+        ] {
+            let mut mail = sample_mail(subject, to);
+            storage.insert_mail(&mut mail, b"", &[]).unwrap();
+            let file_name = format!("{}_note.txt", mail.id);
+            std::fs::write(attachments_dir.join(&file_name), "note").unwrap();
+            storage
+                .insert_attachment(&mut models::Attachment {
+                    id: 0,
+                    mail_id: mail.id,
+                    filename: "note.txt".to_string(),
+                    content_type: "text/plain".to_string(),
+                    content_disposition: Some("attachment".to_string()),
+                    size_bytes: 4,
+                    file_url: format!("/attachments/{}", file_name),
+                })
+                .unwrap();
+            ids.push(mail.id);
+        }
+        let unread = |storage: &Storage| storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap().unread;
+
+        let alice = query::parse("to:alice").unwrap();
+        assert_eq!(storage.set_read_matching(&alice, true).unwrap(), [ids[0], ids[1]]);
+        assert!(storage.set_read_matching(&alice, true).unwrap().is_empty());
+        assert_eq!(unread(&storage), 1);
+        assert_eq!(storage.set_read_matching(&storage::MailFilter::default(), false).unwrap(), [ids[0], ids[1]]);
+        assert_eq!(unread(&storage), 3);
+
+        let deleted = storage.delete_matching(&query::parse("subject:welcome").unwrap()).unwrap();
+        let deleted: Vec<_> = deleted.iter().map(|mail| (mail.id, mail.attachments.len())).collect();
+        assert_eq!(deleted, [(ids[0], 1), (ids[2], 1)]);
+        assert!(!attachments_dir.join(format!("{}_note.txt", ids[0])).exists());
+        assert!(attachments_dir.join(format!("{}_note.txt", ids[1])).exists());
+        assert_eq!(storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap().total, 1);
+
+        assert_eq!(storage.clear_mails().unwrap(), 1);
+        assert_eq!(storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap().total, 0);
+        assert_eq!(std::fs::read_dir(&attachments_dir).unwrap().count(), 0);
+        assert_eq!(storage.clear_mails().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_set_read_publishes_only_changes() {
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let mut receiver = events.subscribe();
+        let mut mail = sample_mail("Hello", "alice@example.com");
+        storage.insert_mail(&mut mail, b"", &[]).unwrap();
+
+        let read = rest_server::set_read_and_publish(&storage, &events, mail.id, true).unwrap().unwrap();
+        assert!(read.is_read);
+        let models::MailEvent::Updated(updated) = receiver.try_recv().unwrap().event else {
+            panic!("expected a mail.updated event");
+        };
+        assert_eq!((updated.id, updated.is_read), (mail.id, true));
+
+        // Nothing changes, nothing is published
+        assert!(rest_server::set_read_and_publish(&storage, &events, mail.id, true).unwrap().unwrap().is_read);
+        assert!(receiver.try_recv().is_err());
+
+        assert!(!rest_server::set_read_and_publish(&storage, &events, mail.id, false).unwrap().unwrap().is_read);
+        assert!(!storage.get_mail(mail.id).unwrap().unwrap().is_read);
+        assert!(receiver.try_recv().is_ok());
+        assert!(rest_server::set_read_and_publish(&storage, &events, 999, true).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rest_errors_are_typed_json() {
+        use axum::body::Body;
+        use axum::http::{Method, Request, StatusCode};
+        use tower::ServiceExt;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let mut mail = sample_mail("Hello", "alice@example.com");
+        storage.insert_mail(&mut mail, b"", &[]).unwrap();
+        let router = Arc::new(RestServer::new(storage.clone(), events)).router();
+
+        let call = |method: Method, uri: String| {
+            let router = router.clone();
+            async move {
+                let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, body) = call(Method::GET, "/api/mails/999".to_string()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "mail_not_found");
+        assert_eq!(body["message"], "Mail 999 not found");
+
+        let (status, body) = call(Method::GET, "/api/mails/abc".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("bad_request")));
+        let (status, body) = call(Method::GET, "/api/mails?q=before:yesterday".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_query")));
+        let (status, body) = call(Method::GET, "/api/mails?limit=ten".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("bad_request")));
+        let (status, body) = call(Method::GET, "/api/mails?recipient=alice@example.com&kind=foo".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("bad_request")));
+        let (status, body) = call(Method::GET, "/api/nothing-here".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::NOT_FOUND, Some("not_found")));
+
+        // Deleting answers 204 once, then 404
+        let (status, _) = call(Method::DELETE, format!("/api/mails/{}", mail.id)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(Method::DELETE, format!("/api/mails/{}", mail.id)).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::NOT_FOUND, Some("mail_not_found")));
+        let (status, body) = call(Method::DELETE, "/api/webhooks/999".to_string()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::NOT_FOUND, Some("webhook_not_found")));
+
+        // Mails deleted are reported so even when the event cannot be published
+        let mut other = sample_mail("Hello again", "alice@example.com");
+        storage.insert_mail(&mut other, b"", &[]).unwrap();
+        storage.conn().unwrap().execute("DROP TABLE events", []).unwrap();
+        let (status, body) = call(Method::DELETE, "/api/mails?q=subject:again".to_string()).await;
+        assert_eq!((status, body["deleted"].as_i64()), (StatusCode::OK, Some(1)));
+
+        // The cause of internal errors stays in the logs
+        let error = api_error::ApiError::from(db::DbError::SchemaTooNew { found: 9, supported: 7 });
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = error.body();
+        assert_eq!((body.code, body.message.as_str()), ("internal_error", "Internal server error"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_mail_over_http() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let router = Arc::new(RestServer::new(storage.clone(), events.clone())).router();
+        let wait = |uri: String| {
+            let router = router.clone();
+            async move {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        // A match already there is answered right away
+        let mut old = sample_mail("Confirm your account", "alice@example.com");
+        storage.insert_mail(&mut old, b"", &[]).unwrap();
+        let (status, body) = wait("/api/mails/wait?subject=confirm&timeout=1s".to_string()).await;
+        assert_eq!((status, body["id"].as_i64()), (StatusCode::OK, Some(old.id)));
+
+        // Past `after_id`, the wait lasts until a match arrives
+        let pending = tokio::spawn(wait(format!("/api/mails/wait?subject=confirm&after_id={}&timeout=5s", old.id)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+        let mut new = sample_mail("Confirm your account", "bob@example.com");
+        storage.insert_mail(&mut new, b"", &[]).unwrap();
+        events.publish(models::MailEvent::Created(new.clone())).unwrap();
+        let (status, body) = pending.await.unwrap();
+        assert_eq!((status, body["id"].as_i64()), (StatusCode::OK, Some(new.id)));
+
+        let (status, body) = wait(format!("/api/mails/wait?subject=confirm&after_id={}&timeout=50ms", new.id)).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::REQUEST_TIMEOUT, Some("timeout")));
+    }
+
+    #[test]
+    fn test_openapi_schemas_match_serialized_models() {
+        use utoipa::OpenApi;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let mut mail = sample_mail("Hello", "alice@example.com");
+        storage.insert_mail(&mut mail, b"", &[]).unwrap();
+        let page = storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap();
+        let webhook = storage.insert_webhook("http://localhost/hook", Some("secret"), &[]).unwrap();
+
+        let spec = serde_json::to_value(openapi::ApiDoc::openapi()).unwrap();
+        let schema_fields = |name: &str| {
+            let mut fields: Vec<String> =
+                spec["components"]["schemas"][name]["properties"].as_object().unwrap().keys().cloned().collect();
+            fields.sort();
+            fields
+        };
+        let json_fields = |value: serde_json::Value| {
+            let mut fields: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+            fields.sort();
+            fields
+        };
+        assert_eq!(schema_fields("StoredMail"), json_fields(serde_json::to_value(&mail).unwrap()));
+        assert_eq!(schema_fields("MailSummary"), json_fields(serde_json::to_value(&page.items[0]).unwrap()));
+        assert_eq!(schema_fields("MailPage"), json_fields(serde_json::to_value(&page).unwrap()));
+        assert_eq!(schema_fields("Envelope"), json_fields(serde_json::to_value(&mail.envelope).unwrap()));
+        assert_eq!(schema_fields("Webhook"), json_fields(serde_json::to_value(&webhook).unwrap()));
+        let rule = chaos::Chaos::default()
+            .add(models::ChaosRule {
+                id: 0,
+                stage: models::ChaosStage::Rcpt,
+                code: Some(550),
+                message: Some("No".to_string()),
+                recipient: None,
+                sender: None,
+                percent: None,
+                remaining: None,
+                delay_ms: None,
+                bytes_per_second: None,
+            })
+            .unwrap();
+        assert_eq!(schema_fields("ChaosRule"), json_fields(serde_json::to_value(&rule).unwrap()));
+        let latency = models::Latency::default();
+        assert_eq!(schema_fields("Latency"), json_fields(serde_json::to_value(&latency).unwrap()));
+
+        // Every operation points at schemas that exist
+        let text = spec.to_string();
+        for reference in text.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(spec["components"]["schemas"].get(name).is_some(), "missing schema {}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openapi_paths_match_router() {
+        use axum::body::Body;
+        use axum::http::{Method, Request, StatusCode};
+        use std::collections::BTreeSet;
+        use tower::ServiceExt;
+        use utoipa::OpenApi;
+
+        let spec = serde_json::to_value(openapi::ApiDoc::openapi()).unwrap();
+        let documented: BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| item.as_object().unwrap().keys().map(move |method| (method.clone(), path.clone())))
+            .collect();
+
+        // The router cannot list its routes, its source can. The documentation
+        // itself and the catch-all route are not operations.
+        let route = regex::Regex::new(r#"\.route\("([^"]+)", (get|post|put|patch|delete)\("#).unwrap();
+        let routed: BTreeSet<(String, String)> = route
+            .captures_iter(include_str!("rest_server.rs"))
+            .map(|captures| (captures[2].to_string(), captures[1].replace(":id", "{id}")))
+            .filter(|(_, path)| path != "/api/openapi.json" && path != "/api/docs")
+            .collect();
+        assert_eq!(documented, routed);
+
+        // And every documented operation is served by a handler of its own
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let router = Arc::new(RestServer::new(storage, events)).router();
+        for (method, path) in documented {
+            let uri = format!("{}?timeout=1ms", path.replace("{id}", "999"));
+            let request = Request::builder()
+                .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+                .uri(&uri)
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+            if status == StatusCode::NOT_FOUND {
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_ne!(body["code"], "not_found", "{} {}", method, path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_smtp_auth_records_authenticated_user() {
+        use std::io::BufReader;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let events = EventBus::new(storage.clone(), 10);
+        let auth = SmtpAuth::users("alice:secret, bob:hunter2").unwrap();
+        assert!(auth.accepts("alice", "secret") && !auth.accepts("alice", "hunter2"));
+        assert!(SmtpAuth::users("alice").is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), events).with_auth(auth);
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut client = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(send(&mut client, "").starts_with("220"));
+        assert!(send(&mut client, "EHLO client.test").contains("AUTH PLAIN LOGIN"));
+        // Refused until authenticated
+        let reply = send(&mut client, "MAIL FROM:<app@example.com>");
+        assert!(reply.starts_with("503"), "{}", reply);
+        // \0bob\0secret
+        assert!(send(&mut client, "AUTH PLAIN AGJvYgBzZWNyZXQ=").starts_with("535"));
+        assert!(send(&mut client, "AUTH LOGIN").starts_with("334"));
+        assert!(send(&mut client, "YWxpY2U=").starts_with("334"));
+        assert!(send(&mut client, "c2VjcmV0").starts_with("235"));
+        assert!(send(&mut client, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert!(send(&mut client, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut client, "DATA").starts_with("354"));
+        assert!(send(&mut client, "Subject: Hello\r\n\r\nHi\r\n.").starts_with("250"));
+        assert!(send(&mut client, "QUIT").starts_with("221"));
+
+        let page = storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap();
+        let mail = storage.get_mail(page.items[0].id).unwrap().unwrap();
+        assert_eq!(mail.envelope.auth_user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_starttls_with_generated_certificate() {
+        use std::io::BufReader;
+
+        let (temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let tls_dir = temp_dir.path().join("tls");
+        let config = tls::self_signed_config(&tls_dir).unwrap();
+        let cert_pem = std::fs::read(tls_dir.join("cert.pem")).unwrap();
+        // Kept across restarts
+        tls::self_signed_config(&tls_dir).unwrap();
+        assert_eq!(std::fs::read(tls_dir.join("cert.pem")).unwrap(), cert_pem);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(tls_dir.join("key.pem")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), EventBus::new(storage.clone(), 10)).with_tls(config);
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut plain = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(send(&mut plain, "").starts_with("220"));
+        assert!(send(&mut plain, "EHLO client.test").contains("STARTTLS"));
+        assert!(send(&mut plain, "STARTTLS").starts_with("220"));
+
+        let mut secure = BufReader::new(tls_client(&cert_pem, plain.into_inner()));
+        assert!(!send(&mut secure, "EHLO client.test").contains("STARTTLS"));
+        assert!(send(&mut secure, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert!(send(&mut secure, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut secure, "DATA").starts_with("354"));
+        assert!(send(&mut secure, "Subject: Secret\r\n\r\nHi\r\n.").starts_with("250"));
+        assert!(send(&mut secure, "QUIT").starts_with("221"));
+
+        let page = storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap();
+        let mail = storage.get_mail(page.items[0].id).unwrap().unwrap();
+        let tls = mail.envelope.tls.expect("TLS parameters recorded");
+        assert_eq!(tls.protocol, "TLSv1.3");
+        assert!(tls.cipher.starts_with("TLS13_"), "{}", tls.cipher);
+    }
+
+    #[test]
+    fn test_smtps_speaks_tls_from_the_first_byte() {
+        use std::io::BufReader;
+
+        let (temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let tls_dir = temp_dir.path().join("tls");
+        let config = tls::self_signed_config(&tls_dir).unwrap();
+        let cert_pem = std::fs::read(tls_dir.join("cert.pem")).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), EventBus::new(storage.clone(), 10))
+            .with_auth(SmtpAuth::AnyCredentials)
+            .with_tls(config);
+        std::thread::spawn(move || server.serve_tls(listener));
+
+        let mut secure = BufReader::new(tls_client(&cert_pem, std::net::TcpStream::connect(addr).unwrap()));
+        assert!(send(&mut secure, "").starts_with("220"));
+        let ehlo = send(&mut secure, "EHLO client.test");
+        assert!(!ehlo.contains("STARTTLS"), "{}", ehlo);
+        assert!(ehlo.contains("AUTH"), "{}", ehlo);
+        assert!(send(&mut secure, "AUTH PLAIN AGJvYgBodW50ZXIy").starts_with("235"));
+        assert!(send(&mut secure, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert!(send(&mut secure, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut secure, "DATA").starts_with("354"));
+        assert!(send(&mut secure, "Subject: Implicit\r\n\r\nHi\r\n.").starts_with("250"));
+        assert!(send(&mut secure, "QUIT").starts_with("221"));
+
+        let page = storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap();
+        let mail = storage.get_mail(page.items[0].id).unwrap().unwrap();
+        assert_eq!(mail.subject, "Implicit");
+        assert_eq!(mail.envelope.auth_user.as_deref(), Some("bob"));
+        assert_eq!(mail.envelope.tls.expect("TLS parameters recorded").protocol, "TLSv1.3");
+    }
+
+    #[tokio::test]
+    async fn test_chaos_rules_inject_smtp_failures() {
+        use axum::body::Body;
+        use axum::http::{Method, Request, StatusCode, header};
+        use std::io::BufReader;
+        use tower::ServiceExt;
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let chaos = Arc::new(Chaos::default());
+        let rest = RestServer::new(storage.clone(), EventBus::new(storage.clone(), 10)).with_chaos(Arc::clone(&chaos));
+        let router = Arc::new(rest).router();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), EventBus::new(storage.clone(), 10)).with_chaos(chaos);
+        std::thread::spawn(move || server.serve(listener));
+
+        let call = |method: Method, uri: &str, body: serde_json::Value| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let none = serde_json::Value::Null;
+
+        let (status, _) = call(Method::POST, "/api/chaos/rules", serde_json::json!({"stage": "rcpt", "code": 250})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let invalid = serde_json::json!({"stage": "rcpt", "code": 550, "recipient": "("});
+        let (status, _) = call(Method::POST, "/api/chaos/rules", invalid).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let unknown = serde_json::json!({"stage": "rcpt", "code": 550, "message": "5.1.1 No such user", "recipient": "^ghost@"});
+        let (status, rule) = call(Method::POST, "/api/chaos/rules", unknown).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(rule["remaining"], none);
+        let full = serde_json::json!({"stage": "data_end", "code": 452, "sender": "@retry\\.test$", "times": 1});
+        let (status, rule) = call(Method::POST, "/api/chaos/rules", full).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(rule["message"], "Simulated temporary failure");
+
+        let mut smtp = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(send(&mut smtp, "").starts_with("220"));
+        assert!(send(&mut smtp, "EHLO client.test").starts_with("250"));
+        assert!(send(&mut smtp, "MAIL FROM:<app@example.com>").starts_with("250"));
+        assert_eq!(send(&mut smtp, "RCPT TO:<ghost@example.com>"), "550 5.1.1 No such user\r\n");
+        assert!(send(&mut smtp, "RCPT TO:<user@example.com>").starts_with("250"));
+        assert!(send(&mut smtp, "DATA").starts_with("354"));
+        assert!(send(&mut smtp, "Subject: First\r\n\r\nHi\r\n.").starts_with("250"));
+
+        // Refused once at the end of data, then the retry in the same session goes through
+        for expected in ["452", "250"] {
+            assert!(send(&mut smtp, "MAIL FROM:<app@retry.test>").starts_with("250"));
+            assert!(send(&mut smtp, "RCPT TO:<user@example.com>").starts_with("250"));
+            assert!(send(&mut smtp, "DATA").starts_with("354"));
+            assert!(send(&mut smtp, "Subject: Retried\r\n\r\nHi\r\n.").starts_with(expected));
+        }
+        assert!(send(&mut smtp, "QUIT").starts_with("221"));
+        let page = storage.list_summaries(&storage::MailFilter::default(), 10, 0).unwrap();
+        assert_eq!(page.total, 2);
+
+        // Counted per message, not per recipient
+        let twice = serde_json::json!({"stage": "rcpt", "code": 450, "recipient": "@busy\\.test$", "times": 2});
+        let (status, _) = call(Method::POST, "/api/chaos/rules", twice).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let mut smtp = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(send(&mut smtp, "").starts_with("220"));
+        assert!(send(&mut smtp, "EHLO client.test").starts_with("250"));
+        for expected in ["450", "450", "250"] {
+            assert!(send(&mut smtp, "MAIL FROM:<app@example.com>").starts_with("250"));
+            assert!(send(&mut smtp, "RCPT TO:<a@busy.test>").starts_with(expected));
+            assert!(send(&mut smtp, "RCPT TO:<b@busy.test>").starts_with(expected));
+            assert!(send(&mut smtp, "RSET").starts_with("250"));
+        }
+        assert!(send(&mut smtp, "QUIT").starts_with("221"));
+
+        // The spent rules are gone
+        let (_, rules) = call(Method::GET, "/api/chaos/rules", none.clone()).await;
+        assert_eq!(rules.as_array().unwrap().len(), 1);
+        let (status, body) = call(Method::DELETE, "/api/chaos/rules/999", none.clone()).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::NOT_FOUND, Some("chaos_rule_not_found")));
+        let (_, body) = call(Method::DELETE, "/api/chaos/rules", none.clone()).await;
+        assert_eq!(body["deleted"], 1);
+
+        // Rules must do something, and only DATA can be throttled
+        let idle = serde_json::json!({"stage": "rcpt"});
+        assert_eq!(call(Method::POST, "/api/chaos/rules", idle).await.0, StatusCode::BAD_REQUEST);
+        let throttled = serde_json::json!({"stage": "rcpt", "bytes_per_second": 100});
+        assert_eq!(call(Method::POST, "/api/chaos/rules", throttled).await.0, StatusCode::BAD_REQUEST);
+        let slow = serde_json::json!({"stage": "data", "delay_ms": 10, "bytes_per_second": 100});
+        let (status, rule) = call(Method::POST, "/api/chaos/rules", slow).await;
+        assert_eq!((status, rule["code"].clone()), (StatusCode::CREATED, none.clone()));
+
+        let (status, _) = call(Method::PUT, "/api/chaos/latency", serde_json::json!({"bytes_per_second": 0})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, latency) = call(Method::PUT, "/api/chaos/latency", serde_json::json!({"command_ms": 5})).await;
+        assert_eq!((status, latency["command_ms"].as_u64()), (StatusCode::OK, Some(5)));
+        let (_, latency) = call(Method::GET, "/api/chaos/latency", none).await;
+        assert_eq!((latency["command_ms"].as_u64(), latency["greeting_ms"].as_u64()), (Some(5), Some(0)));
+    }
+
+    #[test]
+    fn test_latency_slows_smtp_sessions() {
+        use std::io::BufReader;
+        use std::time::{Duration, Instant};
+
+        let (_temp_dir, db_path) = setup_test_db();
+        let storage = Storage::open(&db_path).unwrap();
+        let chaos = Arc::new(Chaos::default());
+        chaos.set_latency(models::Latency {
+            greeting_ms: 200,
+            command_ms: 50,
+            data_end_ms: 300,
+            bytes_per_second: None,
+        });
+        chaos
+            .add(models::ChaosRule {
+                id: 0,
+                stage: models::ChaosStage::Rcpt,
+                code: None,
+                message: None,
+                recipient: Some("^slow@".to_string()),
+                sender: None,
+                percent: None,
+                remaining: None,
+                delay_ms: Some(200),
+                bytes_per_second: None,
+            })
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SmtpServer::new(storage.clone(), EventBus::new(storage.clone(), 10)).with_chaos(Arc::clone(&chaos));
+        std::thread::spawn(move || server.serve(listener));
+
+        let timed = |smtp: &mut BufReader<std::net::TcpStream>, command: &str, code: &str| {
+            let started = Instant::now();
+            let reply = send(smtp, command);
+            assert!(reply.starts_with(code), "{}", reply);
+            started.elapsed()
+        };
+        let mut smtp = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+        assert!(timed(&mut smtp, "", "220") >= Duration::from_millis(200));
+        assert!(timed(&mut smtp, "EHLO client.test", "250") >= Duration::from_millis(50));
+        timed(&mut smtp, "MAIL FROM:<app@example.com>", "250");
+        // The delay of the rule comes on top of the one of every command
+        assert!(timed(&mut smtp, "RCPT TO:<slow@example.com>", "250") >= Duration::from_millis(250));
+        timed(&mut smtp, "DATA", "354");
+        assert!(timed(&mut smtp, "Subject: Slow\r\n\r\nHi\r\n.", "250") >= Duration::from_millis(300));
+
+        // Changes apply to open sessions, 1000 bytes at 4000 bytes per second take a quarter second
+        chaos.set_latency(models::Latency {
+            bytes_per_second: Some(4000),
+            ..Default::default()
+        });
+        timed(&mut smtp, "MAIL FROM:<app@example.com>", "250");
+        timed(&mut smtp, "RCPT TO:<user@example.com>", "250");
+        timed(&mut smtp, "DATA", "354");
+        let content = format!("Subject: Big\r\n\r\n{}\r\n.", "x".repeat(1000));
+        assert!(timed(&mut smtp, &content, "250") >= Duration::from_millis(250));
+        assert!(timed(&mut smtp, "QUIT", "221") < Duration::from_millis(200));
+    }
+
+    // Send an SMTP command, or nothing when empty, and return the whole reply
+    fn send<S: std::io::Read + std::io::Write>(stream: &mut std::io::BufReader<S>, command: &str) -> String {
+        use std::io::BufRead;
+        if !command.is_empty() {
+            stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+            stream.get_mut().flush().unwrap();
+        }
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            reply.push_str(&line);
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
+
+    // Client side of TLS over `stream`, trusting only `cert_pem`
+    fn tls_client(
+        cert_pem: &[u8],
+        stream: std::net::TcpStream,
+    ) -> rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream> {
+        use rustls_pki_types::pem::PemObject;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(rustls_pki_types::CertificateDer::from_pem_slice(cert_pem).unwrap()).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+        rustls::StreamOwned::new(conn, stream)
+    }
 }
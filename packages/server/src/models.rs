@@ -1,6 +1,7 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, ToSchema)]
 pub struct Attachment {
     pub id: i64,
     pub mail_id: i64,
@@ -11,7 +12,38 @@ pub struct Attachment {
     pub file_url: String, // Path to file on disk
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct MailHeader {
+    pub name: String,
+    pub raw_value: String, // As found in the message, folding included
+    pub value: String, // Unfolded and MIME-decoded
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct Recipient {
+    pub name: String,
+    pub address: String,
+}
+
+/// SMTP envelope as seen by the server, independent of the message headers
+#[derive(Debug, Serialize, Clone, Default, ToSchema)]
+pub struct Envelope {
+    pub helo_domain: String,
+    pub mail_from: String, // Return-path, empty for null sender
+    pub rcpt_to: Vec<String>,
+    pub is_8bit: bool, // BODY=8BITMIME was requested
+    pub auth_user: Option<String>, // Username given to SMTP AUTH, missing when not authenticated
+    pub tls: Option<TlsInfo>, // Missing when the message was sent in plaintext
+}
+
+/// TLS parameters negotiated by an SMTP session
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct TlsInfo {
+    pub protocol: String, // e.g. "TLSv1.3"
+    pub cipher: String, // IANA cipher suite name
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
 pub struct StoredMail {
     pub id: i64,
     pub from_address: String,
@@ -23,6 +55,170 @@ pub struct StoredMail {
     pub text: String,
     pub date: String,
     pub is_read: bool,
+    pub parse_warnings: Vec<String>, // Problems found while parsing the message
+    pub envelope: Envelope,
+    pub to: Vec<Recipient>,
+    pub cc: Vec<Recipient>,
+    pub bcc: Vec<Recipient>,
+    pub reply_to: Vec<Recipient>,
     pub attachments: Vec<Attachment>,
 }
 
+/// Lightweight representation used by the mail list, without bodies
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct MailSummary {
+    pub id: i64,
+    pub from_address: String,
+    pub from_name: String,
+    pub to_address: String,
+    pub to_name: String,
+    pub subject: String,
+    pub preview: String, // Start of the text body
+    pub date: String,
+    pub is_read: bool,
+    pub attachment_count: i64,
+    pub snippet: Option<String>, // Search match with <mark> highlights, only when searching
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct MailPage {
+    pub items: Vec<MailSummary>,
+    pub total: i64, // Mails matching the filters, across all pages
+    pub unread: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// A link found in a mail body
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ExtractedLink {
+    pub url: String,
+    pub text: String, // Anchor text, empty for links found in the text body
+    pub source: String, // "html" or "text"
+}
+
+/// Something that happened to the mailbox, published to SSE and WebSocket
+/// subscribers and webhooks
+#[derive(Debug, Clone)]
+pub enum MailEvent {
+    Created(StoredMail),
+    Updated(StoredMail), // Carries the new state, e.g. after being marked read
+    Deleted(StoredMail), // Carries the mail as it was before deletion
+    Cleared, // Every mail was deleted at once
+    DeletedMany(Vec<i64>), // Ids of the mails deleted at once by a filter
+    UpdatedMany { ids: Vec<i64>, is_read: bool }, // Ids of the mails changed at once by a filter
+}
+
+impl MailEvent {
+    /// Every name returned by `name`
+    pub const NAMES: &[&str] = &[
+        "mail.created",
+        "mail.updated",
+        "mail.deleted",
+        "inbox.cleared",
+        "mails.deleted",
+        "mails.updated",
+    ];
+    /// Names of the events the log keeps too little of to replay them
+    pub const BULK_NAMES: &[&str] = &["mails.deleted", "mails.updated"];
+
+    /// Name used on the wire, as the SSE event type or in `X-Webhook-Event`
+    pub fn name(&self) -> &'static str {
+        match self {
+            MailEvent::Created(_) => "mail.created",
+            MailEvent::Updated(_) => "mail.updated",
+            MailEvent::Deleted(_) => "mail.deleted",
+            MailEvent::Cleared => "inbox.cleared",
+            MailEvent::DeletedMany(_) => "mails.deleted",
+            MailEvent::UpdatedMany { .. } => "mails.updated",
+        }
+    }
+
+    /// The mail concerned, `None` for events about the whole mailbox
+    pub fn mail(&self) -> Option<&StoredMail> {
+        match self {
+            MailEvent::Created(mail) | MailEvent::Updated(mail) | MailEvent::Deleted(mail) => {
+                Some(mail)
+            }
+            MailEvent::Cleared | MailEvent::DeletedMany(_) | MailEvent::UpdatedMany { .. } => None,
+        }
+    }
+}
+
+/// Entry of the event log, see `EventBus::replay`
+#[derive(Debug, Clone)]
+pub struct LoggedEvent {
+    pub id: i64,
+    pub name: String,
+    pub mail_id: Option<i64>,
+}
+
+/// Target notified with a JSON POST when mails are created or deleted
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: Option<String>, // HMAC-SHA256 key, never returned by the API
+    pub signed: bool,
+    pub events: Vec<String>, // Event names to deliver, empty for all
+    pub created_at: String,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &MailEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+/// One delivery attempt of a webhook
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: String,
+    pub event: String,
+    pub mail_id: Option<i64>, // Missing for events about the whole mailbox
+    pub attempt: u32,
+    pub status: Option<u16>, // HTTP status, missing when no response was received
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub delivered_at: String,
+}
+
+/// SMTP step at which a chaos rule answers with an error
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaosStage {
+    /// Each `RCPT TO`, matched against that recipient only
+    Rcpt,
+    /// The `DATA` command, before the content is sent
+    Data,
+    /// The final `.`, once the content was received; the mail is not stored
+    DataEnd,
+}
+
+/// Failure or slowness injected into SMTP sessions, see `chaos::Chaos`
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ChaosRule {
+    pub id: i64,
+    pub stage: ChaosStage,
+    pub code: Option<u16>, // SMTP reply code, 4xx or 5xx; the mail goes on when missing
+    pub message: Option<String>, // Text of the reply, set along with `code`
+    pub recipient: Option<String>, // Regex matched against recipient addresses
+    pub sender: Option<String>, // Regex matched against the MAIL FROM address
+    pub percent: Option<f64>, // Chance to fire for a matching mail, always when missing
+    pub remaining: Option<u32>, // Times left to fire before removal, unlimited when missing
+    pub delay_ms: Option<u64>, // Wait before answering at `stage`
+    pub bytes_per_second: Option<u64>, // Upload rate of the content, for the `data` stage
+}
+
+/// Artificial slowness of every SMTP session, changed with `PUT /api/chaos/latency`
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct Latency {
+    pub greeting_ms: u64, // Before the greeting
+    pub command_ms: u64, // Before each reply to a command
+    pub data_end_ms: u64, // Before the reply to the end of data, instead of `command_ms`
+    pub bytes_per_second: Option<u64>, // In each direction, unlimited when missing
+}
@@ -0,0 +1,402 @@
+//! OpenAPI 3 description of the REST API, served at `/api/openapi.json`.
+//!
+//! Schemas are derived from the models and handler parameters themselves. The
+//! handlers are methods of `RestServer`, which `utoipa::path` cannot annotate,
+//! so each operation is declared on a signature-only function in `paths`, and a
+//! test checks that they list the same method and path pairs as the router.
+
+use crate::api_error::ErrorBody;
+use crate::models::{
+    Attachment, ChaosRule, ChaosStage, Envelope, Latency, ExtractedLink, MailHeader, MailPage, MailSummary, Recipient,
+    StoredMail, TlsInfo, Webhook, WebhookDelivery,
+};
+use crate::rest_server::{
+    BulkChanges, ChaosRuleParams, Deleted, MailChanges, Updated, WebhookParams,
+};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Fastmail SMTP Dev",
+        description = "Inspect, search and manage the mails received by the development SMTP server"
+    ),
+    paths(
+        paths::list_mails,
+        paths::delete_mails,
+        paths::update_mails,
+        paths::wait_for_mail,
+        paths::get_mail,
+        paths::update_mail,
+        paths::delete_mail,
+        paths::get_mail_headers,
+        paths::get_raw_mail,
+        paths::get_eml,
+        paths::get_mail_links,
+        paths::get_mail_codes,
+        paths::list_webhooks,
+        paths::create_webhook,
+        paths::list_deliveries,
+        paths::delete_webhook,
+        paths::list_chaos_rules,
+        paths::create_chaos_rule,
+        paths::clear_chaos_rules,
+        paths::delete_chaos_rule,
+        paths::get_latency,
+        paths::set_latency,
+        paths::events,
+        paths::websocket,
+    ),
+    components(schemas(
+        Attachment,
+        BulkChanges,
+        ChaosRule,
+        ChaosRuleParams,
+        ChaosStage,
+        Deleted,
+        Envelope,
+        ErrorBody,
+        ExtractedLink,
+        Latency,
+        MailChanges,
+        MailHeader,
+        MailPage,
+        MailSummary,
+        Recipient,
+        StoredMail,
+        TlsInfo,
+        Updated,
+        Webhook,
+        WebhookDelivery,
+        WebhookParams,
+    )),
+    tags(
+        (name = "mails", description = "Received mails"),
+        (name = "webhooks", description = "HTTP targets notified of mail events"),
+        (name = "chaos", description = "SMTP failures and slowness injected on purpose"),
+        (name = "events", description = "Live mail events"),
+    )
+)]
+pub struct ApiDoc;
+
+#[allow(dead_code)]
+mod paths {
+    use crate::rest_server::{
+        CodeParams, DeliveryParams, FilterParams, GetParams, ListParams, WaitParams,
+    };
+    use crate::ws::SubscribeParams;
+
+    /// List mail summaries, most recent first
+    #[utoipa::path(
+        get,
+        path = "/api/mails",
+        tag = "mails",
+        params(ListParams),
+        responses(
+            (status = 200, description = "A page of summaries", body = MailPage),
+            (status = 400, description = "Invalid parameters or search query", body = ErrorBody),
+        )
+    )]
+    fn list_mails() {}
+
+    /// Delete the mails matching the filters, or every mail without filters
+    #[utoipa::path(
+        delete,
+        path = "/api/mails",
+        tag = "mails",
+        params(FilterParams),
+        responses(
+            (status = 200, description = "Number of deleted mails", body = Deleted),
+            (status = 400, description = "Invalid parameters or search query", body = ErrorBody),
+        )
+    )]
+    fn delete_mails() {}
+
+    /// Mark the mails matching the filters read or unread
+    #[utoipa::path(
+        patch,
+        path = "/api/mails",
+        tag = "mails",
+        params(FilterParams),
+        request_body = BulkChanges,
+        responses(
+            (status = 200, description = "Number of mails that changed", body = Updated),
+            (status = 400, description = "Invalid parameters, search query or body", body = ErrorBody),
+        )
+    )]
+    fn update_mails() {}
+
+    /// Wait until a matching mail exists, answering with the earliest one in
+    /// arrival order. Pass `after_id` to skip mails stored before the wait.
+    #[utoipa::path(
+        get,
+        path = "/api/mails/wait",
+        tag = "mails",
+        params(WaitParams),
+        responses(
+            (status = 200, description = "The earliest matching mail", body = StoredMail),
+            (status = 400, description = "Invalid parameters or search query", body = ErrorBody),
+            (status = 408, description = "No matching mail before the timeout", body = ErrorBody),
+        )
+    )]
+    fn wait_for_mail() {}
+
+    /// Fetch a mail, without marking it read unless `mark_read` is set
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id"), GetParams),
+        responses(
+            (status = 200, description = "The mail", body = StoredMail),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn get_mail() {}
+
+    /// Change the flags of a mail
+    #[utoipa::path(
+        patch,
+        path = "/api/mails/{id}",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        request_body = MailChanges,
+        responses(
+            (status = 200, description = "The mail in its new state", body = StoredMail),
+            (status = 400, description = "Invalid body", body = ErrorBody),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn update_mail() {}
+
+    /// Delete a mail and its attachments
+    #[utoipa::path(
+        delete,
+        path = "/api/mails/{id}",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        responses(
+            (status = 204, description = "Deleted"),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn delete_mail() {}
+
+    /// List every header of a mail in message order
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}/headers",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        responses(
+            (status = 200, description = "The headers", body = [MailHeader]),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn get_mail_headers() {}
+
+    /// Original RFC 5322 source of a mail
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}/raw",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        responses(
+            (status = 200, description = "The source, inline", body = String, content_type = "message/rfc822"),
+            (status = 404, description = "No such mail, or no source stored for it", body = ErrorBody),
+        )
+    )]
+    fn get_raw_mail() {}
+
+    /// Original RFC 5322 source of a mail, as an `.eml` download
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}/eml",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        responses(
+            (status = 200, description = "The source, as an attachment", body = String, content_type = "message/rfc822"),
+            (status = 404, description = "No such mail, or no source stored for it", body = ErrorBody),
+        )
+    )]
+    fn get_eml() {}
+
+    /// Links of a mail, HTML anchors first, then URLs of the text body
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}/links",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id")),
+        responses(
+            (status = 200, description = "The links", body = [ExtractedLink]),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn get_mail_links() {}
+
+    /// One-time codes of a mail, matched by `pattern` or by digit count
+    #[utoipa::path(
+        get,
+        path = "/api/mails/{id}/codes",
+        tag = "mails",
+        params(("id" = i64, Path, description = "Mail id"), CodeParams),
+        responses(
+            (status = 200, description = "The codes, in order of appearance", body = [String]),
+            (status = 400, description = "Invalid pattern or length range", body = ErrorBody),
+            (status = 404, description = "No such mail", body = ErrorBody),
+        )
+    )]
+    fn get_mail_codes() {}
+
+    /// List registered webhooks
+    #[utoipa::path(
+        get,
+        path = "/api/webhooks",
+        tag = "webhooks",
+        responses((status = 200, description = "The webhooks", body = [Webhook]))
+    )]
+    fn list_webhooks() {}
+
+    /// Register a webhook
+    #[utoipa::path(
+        post,
+        path = "/api/webhooks",
+        tag = "webhooks",
+        request_body = WebhookParams,
+        responses(
+            (status = 201, description = "The registered webhook", body = Webhook),
+            (status = 400, description = "Invalid URL or event", body = ErrorBody),
+        )
+    )]
+    fn create_webhook() {}
+
+    /// Delivery attempts, most recent first
+    #[utoipa::path(
+        get,
+        path = "/api/webhooks/deliveries",
+        tag = "webhooks",
+        params(DeliveryParams),
+        responses((status = 200, description = "The attempts", body = [WebhookDelivery]))
+    )]
+    fn list_deliveries() {}
+
+    /// Unregister a webhook
+    #[utoipa::path(
+        delete,
+        path = "/api/webhooks/{id}",
+        tag = "webhooks",
+        params(("id" = i64, Path, description = "Webhook id")),
+        responses(
+            (status = 204, description = "Unregistered"),
+            (status = 404, description = "No such webhook", body = ErrorBody),
+        )
+    )]
+    fn delete_webhook() {}
+
+    /// List chaos rules in the order they are checked
+    #[utoipa::path(
+        get,
+        path = "/api/chaos/rules",
+        tag = "chaos",
+        responses((status = 200, description = "The rules", body = [ChaosRule]))
+    )]
+    fn list_chaos_rules() {}
+
+    /// Add a rule refusing, delaying or throttling matching mails
+    #[utoipa::path(
+        post,
+        path = "/api/chaos/rules",
+        tag = "chaos",
+        request_body = ChaosRuleParams,
+        responses(
+            (status = 201, description = "The added rule", body = ChaosRule),
+            (status = 400, description = "Invalid code, rate, percentage, count or pattern", body = ErrorBody),
+        )
+    )]
+    fn create_chaos_rule() {}
+
+    /// Remove every chaos rule
+    #[utoipa::path(
+        delete,
+        path = "/api/chaos/rules",
+        tag = "chaos",
+        responses((status = 200, description = "Number of removed rules", body = Deleted))
+    )]
+    fn clear_chaos_rules() {}
+
+    /// Remove a chaos rule
+    #[utoipa::path(
+        delete,
+        path = "/api/chaos/rules/{id}",
+        tag = "chaos",
+        params(("id" = i64, Path, description = "Rule id")),
+        responses(
+            (status = 204, description = "Removed"),
+            (status = 404, description = "No such rule", body = ErrorBody),
+        )
+    )]
+    fn delete_chaos_rule() {}
+
+    /// Latency applied to every SMTP session
+    #[utoipa::path(
+        get,
+        path = "/api/chaos/latency",
+        tag = "chaos",
+        responses((status = 200, description = "The current settings", body = Latency))
+    )]
+    fn get_latency() {}
+
+    /// Replace the latency of every SMTP session, open ones included; missing fields reset
+    #[utoipa::path(
+        put,
+        path = "/api/chaos/latency",
+        tag = "chaos",
+        request_body = Latency,
+        responses(
+            (status = 200, description = "The new settings", body = Latency),
+            (status = 400, description = "Invalid body or rate", body = ErrorBody),
+        )
+    )]
+    fn set_latency() {}
+
+    /// Server-sent events named `mail.created`, `mail.updated`, `mail.deleted`,
+    /// `inbox.cleared`, `mails.deleted`, `mails.updated` and `resync`,
+    /// resumable with `Last-Event-ID`
+    #[utoipa::path(
+        get,
+        path = "/api/events",
+        tag = "events",
+        params(("Last-Event-ID" = Option<i64>, Header, description = "Id of the last event received")),
+        responses((status = 200, description = "The event stream", body = String, content_type = "text/event-stream"))
+    )]
+    fn events() {}
+
+    /// WebSocket streaming the same events as `/api/events`, filtered by
+    /// subscription, and accepting `subscribe`, `mark_read` and `delete` commands
+    #[utoipa::path(
+        get,
+        path = "/api/ws",
+        tag = "events",
+        params(SubscribeParams),
+        responses((status = 101, description = "Switched to the WebSocket protocol"))
+    )]
+    fn websocket() {}
+}
+
+/// Swagger UI for the document, loaded from a CDN
+pub const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>Fastmail SMTP Dev API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+</body>
+</html>
+"##;
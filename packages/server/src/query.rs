@@ -0,0 +1,118 @@
+use crate::storage::MailFilter;
+use std::fmt;
+use std::time::Duration;
+
+/// Error raised for a search query that cannot be understood
+#[derive(Debug, PartialEq)]
+pub struct QueryError(pub String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Parse a search query such as `from:alice to:bob@example.com subject:"reset password" is:unread`
+/// into a filter. Supported terms:
+///
+/// - `from:`, `to:`, `subject:` substring matches (`to:` covers To, Cc and Bcc, not Reply-To)
+/// - `is:read`, `is:unread`
+/// - `has:attachment`, `no:attachment`
+/// - `before:`, `after:` with a `YYYY-MM-DD` date or an RFC 3339 timestamp
+/// - anything else is free text, searched as a word prefix in subject, sender,
+///   bodies and attachment names and ranked by relevance
+///
+/// Values containing spaces can be wrapped in double quotes.
+pub fn parse(query: &str) -> Result<MailFilter, QueryError> {
+    let mut filter = MailFilter::default();
+
+    for token in tokenize(query) {
+        let Some((key, value)) = token.split_once(':').filter(|(key, _)| is_keyword(key)) else {
+            filter.text.push(token);
+            continue;
+        };
+        if value.is_empty() {
+            return Err(QueryError(format!("Missing value for `{}:`", key)));
+        }
+        match (key.to_ascii_lowercase().as_str(), value.to_ascii_lowercase().as_str()) {
+            ("from", _) => filter.from.push(value.to_string()),
+            ("to", _) => filter.to.push(value.to_string()),
+            ("subject", _) => filter.subject.push(value.to_string()),
+            ("is", "read") => filter.is_read = Some(true),
+            ("is", "unread") => filter.is_read = Some(false),
+            ("has", "attachment" | "attachments") => filter.has_attachment = Some(true),
+            ("no", "attachment" | "attachments") => filter.has_attachment = Some(false),
+            ("before", _) => filter.before = Some(parse_date(value)?),
+            ("after", _) => filter.after = Some(parse_date(value)?),
+            (key, _) => {
+                return Err(QueryError(format!("Unsupported value `{}` for `{}:`", value, key)));
+            }
+        }
+    }
+
+    Ok(filter)
+}
+
+fn is_keyword(key: &str) -> bool {
+    matches!(
+        key.to_ascii_lowercase().as_str(),
+        "from" | "to" | "subject" | "is" | "has" | "no" | "before" | "after"
+    )
+}
+
+/// Split on whitespace, keeping double-quoted sections together and dropping the quotes
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Accept `YYYY-MM-DD`, optionally followed by a time, as understood by SQLite's `julianday`
+fn parse_date(value: &str) -> Result<String, QueryError> {
+    let bytes = value.as_bytes();
+    let is_date = bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+        && (bytes.len() == 10 || matches!(bytes[10], b'T' | b't' | b' '));
+    if is_date {
+        Ok(value.to_string())
+    } else {
+        Err(QueryError(format!("Invalid date `{}`, expected YYYY-MM-DD", value)))
+    }
+}
+
+/// Parse a duration such as `10s`, `500ms`, `2m` or a bare number of seconds
+pub fn parse_duration(value: &str) -> Result<Duration, QueryError> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let invalid = || QueryError(format!("Invalid duration `{}`, expected e.g. 10s or 500ms", value));
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
@@ -1,13 +1,29 @@
-use crate::models::{Attachment, StoredMail};
+use crate::chaos::Chaos;
+use crate::db::DbError;
+use crate::events::{EventBus, WireEvent};
+use crate::extract::{self, CodePattern};
+use crate::models::{
+    ChaosRule, ChaosStage, ExtractedLink, Latency, MailEvent, MailHeader, MailPage, StoredMail,
+    Webhook, WebhookDelivery,
+};
+use crate::openapi::{self, ApiDoc};
+use crate::query;
+use crate::storage::{MailFilter, Storage, RECIPIENT_KINDS};
+use crate::webhooks;
+use crate::ws;
+use crate::api_error::{ApiError, ApiResult, Json, Path, Query};
 use axum::{
-    extract::{Path, Request},
+    extract::Request,
     response::{Html, IntoResponse},
-    routing::{delete, get},
-    Json, Router,
+    routing::{any, delete, get, patch, post, put},
+    Router,
 };
+use axum::extract::ws::WebSocketUpgrade;
+use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use futures_util::stream::Stream;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{convert::Infallible, fs};
 use tokio::sync::broadcast;
 use tokio::net::TcpListener;
@@ -17,21 +33,51 @@ use tower_http::{
     services::ServeDir,
 };
 use async_stream::stream as async_stream;
-use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use utoipa::OpenApi as _;
 
 pub struct RestServer {
-    db_path: String,
-    sender: broadcast::Sender<StoredMail>,
+    storage: Storage,
+    events: EventBus,
+    chaos: Arc<Chaos>,
 }
 
 impl RestServer {
-    pub fn new(db_path: String, sender: broadcast::Sender<StoredMail>) -> Self {
-        Self { db_path, sender }
+    pub fn new(storage: Storage, events: EventBus) -> Self {
+        Self {
+            storage,
+            events,
+            chaos: Arc::default(),
+        }
+    }
+
+    /// Manage the chaos rules shared with the SMTP server
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.chaos = chaos;
+        self
     }
 
     pub async fn run(self: Arc<Self>) {
-        let cors = CorsLayer::new().allow_origin(Any);
         let api_port = std::env::var("API_PORT").unwrap_or_else(|_| "1080".to_string());
+        let app = self.router();
+        let bind_addr = format!("0.0.0.0:{}", api_port);
+        let listener = match TcpListener::bind(&bind_addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("[API] Failed to bind {}: {}", bind_addr, e);
+                return;
+            }
+        };
+        println!("API REST listening on {}", bind_addr);
+        if let Err(e) = axum::serve(listener, app.into_make_service()).await {
+            eprintln!("[API] Server error: {}", e);
+        }
+    }
+
+    /// Routes of the API, the event streams and the web UI
+    pub fn router(self: &Arc<Self>) -> Router {
+        let cors = CorsLayer::new().allow_origin(Any);
         let static_path =
             std::env::var("STATIC_DIR").unwrap_or_else(|_| "/app/public".to_string());
         println!("[Static] Serving static files from: {}", static_path);
@@ -40,175 +86,683 @@ impl RestServer {
             |_req: Request| async { Ok::<_, Infallible>(spa_fallback().await.into_response()) },
         ));
 
-        let app = Router::new()
+        Router::new()
             .route("/api/mails", get({
-                let this = Arc::clone(&self);
-                move || {
+                let this = Arc::clone(self);
+                move |Query(params): Query<ListParams>| {
                     let this = Arc::clone(&this);
-                    async move { this.list_mails().await }
+                    async move { this.list_mails(params).await }
+                }
+            }))
+            .route("/api/mails", delete({
+                let this = Arc::clone(self);
+                move |Query(params): Query<FilterParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.delete_mails(params).await }
+                }
+            }))
+            .route("/api/mails", patch({
+                let this = Arc::clone(self);
+                move |Query(params): Query<FilterParams>, Json(changes): Json<BulkChanges>| {
+                    let this = Arc::clone(&this);
+                    async move { this.update_mails(params, changes).await }
+                }
+            }))
+            .route("/api/mails/wait", get({
+                let this = Arc::clone(self);
+                move |Query(params): Query<WaitParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.wait_for_mail(params).await }
                 }
             }))
             .route("/api/mails/:id", get({
-                let this = Arc::clone(&self);
-                move |Path(id): Path<i64>| {
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>, Query(params): Query<GetParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_mail(id, params).await }
+                }
+            }))
+            .route("/api/mails/:id", patch({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>, Json(changes): Json<MailChanges>| {
                     let this = Arc::clone(&this);
-                    async move { this.get_mail(id).await }
+                    async move { this.update_mail(id, changes).await }
                 }
             }))
             .route("/api/mails/:id", delete({
-                let this = Arc::clone(&self);
+                let this = Arc::clone(self);
                 move |Path(id): Path<i64>| {
                     let this = Arc::clone(&this);
                     async move { this.delete_mail(id).await }
                 }
             }))
+            .route("/api/mails/:id/headers", get({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_mail_headers(id).await }
+                }
+            }))
+            .route("/api/mails/:id/raw", get({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_raw_mail(id, false).await }
+                }
+            }))
+            .route("/api/mails/:id/eml", get({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_raw_mail(id, true).await }
+                }
+            }))
+            .route("/api/mails/:id/links", get({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_mail_links(id).await }
+                }
+            }))
+            .route("/api/mails/:id/codes", get({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>, Query(params): Query<CodeParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.get_mail_codes(id, params).await }
+                }
+            }))
+            .route("/api/webhooks", get({
+                let this = Arc::clone(self);
+                move || {
+                    let this = Arc::clone(&this);
+                    async move { this.list_webhooks().await }
+                }
+            }))
+            .route("/api/webhooks", post({
+                let this = Arc::clone(self);
+                move |Json(params): Json<WebhookParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.create_webhook(params).await }
+                }
+            }))
+            .route("/api/webhooks/deliveries", get({
+                let this = Arc::clone(self);
+                move |Query(params): Query<DeliveryParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.list_deliveries(params).await }
+                }
+            }))
+            .route("/api/webhooks/:id", delete({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.delete_webhook(id).await }
+                }
+            }))
+            .route("/api/chaos/rules", get({
+                let this = Arc::clone(self);
+                move || {
+                    let this = Arc::clone(&this);
+                    async move { this.list_chaos_rules() }
+                }
+            }))
+            .route("/api/chaos/rules", post({
+                let this = Arc::clone(self);
+                move |Json(params): Json<ChaosRuleParams>| {
+                    let this = Arc::clone(&this);
+                    async move { this.create_chaos_rule(params) }
+                }
+            }))
+            .route("/api/chaos/rules", delete({
+                let this = Arc::clone(self);
+                move || {
+                    let this = Arc::clone(&this);
+                    async move { this.clear_chaos_rules() }
+                }
+            }))
+            .route("/api/chaos/rules/:id", delete({
+                let this = Arc::clone(self);
+                move |Path(id): Path<i64>| {
+                    let this = Arc::clone(&this);
+                    async move { this.delete_chaos_rule(id) }
+                }
+            }))
+            .route("/api/chaos/latency", get({
+                let this = Arc::clone(self);
+                move || {
+                    let this = Arc::clone(&this);
+                    async move { this.get_latency() }
+                }
+            }))
+            .route("/api/chaos/latency", put({
+                let this = Arc::clone(self);
+                move |Json(latency): Json<Latency>| {
+                    let this = Arc::clone(&this);
+                    async move { this.set_latency(latency) }
+                }
+            }))
+            .route("/api/ws", get({
+                let this = Arc::clone(self);
+                move |upgrade: WebSocketUpgrade, Query(params): Query<ws::SubscribeParams>| {
+                    let (storage, events) = (this.storage.clone(), this.events.clone());
+                    async move {
+                        upgrade.on_upgrade(move |socket| ws::serve(socket, storage, events, params))
+                    }
+                }
+            }))
             .route("/api/events", get({
-                let sender = self.sender.clone();
-                move || sse_events(sender.clone())
+                let events = self.events.clone();
+                move |headers: HeaderMap| sse_events(events.clone(), headers)
             }))
-            .nest_service("/api/attachments", ServeDir::new("./attachments"))
+            .route("/api/openapi.json", get(|| async { Json(ApiDoc::openapi()) }))
+            .route("/api/docs", get(|| async { Html(openapi::SWAGGER_UI) }))
+            // Unknown API routes answer JSON instead of the web UI
+            .route("/api/*rest", any(|| async { ApiError::NotFound("No such API route".to_string()) }))
+            .nest_service("/api/attachments", ServeDir::new(self.storage.attachments_dir()))
             .nest_service("/", static_files)
-            .layer(cors);
-        let bind_addr = format!("0.0.0.0:{}", api_port);
-        let listener = TcpListener::bind(&bind_addr).await.unwrap();
-        println!("API REST listening on {}", listener.local_addr().unwrap());
-        let _ = axum::serve(listener, app.into_make_service()).await;
-    }
-
-    async fn list_mails(self: Arc<Self>) -> impl IntoResponse {
-        let db_path = self.db_path.clone();
-        let mails = tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&db_path).unwrap();
-            let mut stmt = conn
-                .prepare(
-                    "SELECT id, from_address, from_name, to_address, to_name, subject, html, text, date, is_read FROM mails ORDER BY date DESC",
-                )
-                .unwrap();
-            let mails_iter = stmt.query_map([], |row| {
-                let mail_id = row.get::<_, i64>(0)?;
-                Ok((
-                    mail_id,
-                    StoredMail {
-                        id: mail_id,
-                        from_address: row.get(1)?,
-                        from_name: row.get(2)?,
-                        to_address: row.get(3)?,
-                        to_name: row.get(4)?,
-                        subject: row.get(5)?,
-                        html: row.get(6)?,
-                        text: row.get(7)?,
-                        date: row.get(8)?,
-                        is_read: row.get::<_, i64>(9)? != 0,
-                        attachments: Vec::new(), // Will be loaded below
-                    },
-                ))
-            }).unwrap();
-
-            let mails: Vec<StoredMail> = mails_iter.map(|m| m.unwrap().1).collect();
-            mails
-        })
-        .await
-        .unwrap();
-
-        // Load attachments for each mail
-        let mut mails_with_attachments = Vec::new();
-        for mut mail in mails {
-            mail.attachments = self.load_attachments_for_mail(mail.id).await;
-            mails_with_attachments.push(mail);
-        }
-
-        Json(mails_with_attachments)
-    }
-
-    async fn load_attachments_for_mail(&self, mail_id: i64) -> Vec<Attachment> {
-        let db_path = self.db_path.clone();
-        tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&db_path).unwrap();
-            let mut stmt = conn
-                .prepare(
-                    "SELECT id, mail_id, filename, content_type, content_disposition, size_bytes, file_url FROM attachments WHERE mail_id = ?",
-                )
-                .unwrap();
-            let attachments_iter = stmt.query_map([mail_id], |row| {
-                Ok(Attachment {
-                    id: row.get(0)?,
-                    mail_id: row.get(1)?,
-                    filename: row.get(2)?,
-                    content_type: row.get(3)?,
-                    content_disposition: row.get(4)?,
-                    size_bytes: row.get(5)?,
-                    file_url: row.get(6)?,
-                })
-            }).unwrap();
-
-            attachments_iter.map(|a| a.unwrap()).collect()
-        })
-        .await
-        .unwrap()
+            .layer(cors)
     }
 
-    async fn delete_mail(
-        self: Arc<Self>,
-        id: i64,
-    ) -> Result<(), axum::http::StatusCode> {
-        let db_path = self.db_path.clone();
-        tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&db_path)
-                .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-            conn.execute("DELETE FROM mails WHERE id = ?", [id])
-                .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-            Ok::<(), axum::http::StatusCode>(())
+    async fn list_mails(self: Arc<Self>, params: ListParams) -> ApiResult<Json<MailPage>> {
+        let storage = self.storage.clone();
+        let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let offset = params.offset.unwrap_or(0).max(0);
+        let filter = parse_filter(params.q.as_deref(), params.recipient, params.kind)?;
+        let page = tokio::task::spawn_blocking(move || storage.list_summaries(&filter, limit, offset))
+            .await??;
+
+        Ok(Json(page))
+    }
+
+    /// Long-poll until a mail matching the parameters exists, answering with the
+    /// earliest match in arrival order or 408 once the timeout elapses. Mails
+    /// already stored match too, unless `after_id` skips them.
+    async fn wait_for_mail(self: Arc<Self>, params: WaitParams) -> ApiResult<Json<StoredMail>> {
+        let timeout = match params.timeout.as_deref() {
+            Some(timeout) => query::parse_duration(timeout)?,
+            None => DEFAULT_WAIT_TIMEOUT,
+        }
+        .min(MAX_WAIT_TIMEOUT);
+        let mut filter = match params.q.as_deref() {
+            Some(q) => query::parse(q)?,
+            None => MailFilter::default(),
+        };
+        filter.from.extend(params.from);
+        filter.to.extend(params.to);
+        filter.subject.extend(params.subject);
+        filter.after_id = params.after_id;
+
+        // Subscribe before looking so a mail arriving in between is not missed
+        let mut receiver = self.events.subscribe();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let storage = self.storage.clone();
+            let filter = filter.clone();
+            let found = tokio::task::spawn_blocking(move || storage.first_matching(&filter)).await??;
+            if let Some(mail) = found {
+                return Ok(Json(mail));
+            }
+
+            // Any new mail (or a lag notice) is a reason to look again
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => {
+                    return Err(ApiError::Timeout(format!(
+                        "No matching mail received within {:?}",
+                        timeout
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Delete every mail matching the filters, or clear the inbox when there are none
+    async fn delete_mails(self: Arc<Self>, params: FilterParams) -> ApiResult<Json<Deleted>> {
+        let filter = parse_filter(params.q.as_deref(), params.recipient, params.kind)?;
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let deleted = tokio::task::spawn_blocking(move || {
+            if filter == MailFilter::default() {
+                let deleted = storage.clear_mails()?;
+                publish_or_log(&events, MailEvent::Cleared);
+                return Ok(deleted);
+            }
+            let ids: Vec<_> = storage.delete_matching(&filter)?.iter().map(|mail| mail.id).collect();
+            let count = ids.len();
+            if count > 0 {
+                publish_or_log(&events, MailEvent::DeletedMany(ids));
+            }
+            Ok::<_, DbError>(count)
         })
-        .await
-        .unwrap()
+        .await??;
+        Ok(Json(Deleted { deleted }))
     }
 
-    async fn get_mail(
+    /// Apply the same changes to every mail matching the filters
+    async fn update_mails(
         self: Arc<Self>,
-        id: i64,
-    ) -> Result<Json<StoredMail>, axum::http::StatusCode> {
-        let db_path = self.db_path.clone();
-        let mail_result = tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&db_path)
-                .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-            let mut stmt = conn
-                .prepare(
-                    "SELECT id, from_address, from_name, to_address, to_name, subject, html, text, date, is_read FROM mails WHERE id = ?",
-                )
-                .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-            let mail = stmt
-                .query_row([id], |row| {
-                    Ok(StoredMail {
-                        id: row.get(0)?,
-                        from_address: row.get(1)?,
-                        from_name: row.get(2)?,
-                        to_address: row.get(3)?,
-                        to_name: row.get(4)?,
-                        subject: row.get(5)?,
-                        html: row.get(6)?,
-                        text: row.get(7)?,
-                        date: row.get(8)?,
-                        is_read: row.get::<_, i64>(9)? != 0,
-                        attachments: Vec::new(), // Will be loaded below
-                    })
-                })
-                .map_err(|_| axum::http::StatusCode::NOT_FOUND)?;
-
-            // Mark as read
-            conn.execute("UPDATE mails SET is_read = 1 WHERE id = ?", [id])
-                .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-            Ok::<StoredMail, axum::http::StatusCode>(mail)
+        params: FilterParams,
+        changes: BulkChanges,
+    ) -> ApiResult<Json<Updated>> {
+        let filter = parse_filter(params.q.as_deref(), params.recipient, params.kind)?;
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let updated = tokio::task::spawn_blocking(move || {
+            let ids = storage.set_read_matching(&filter, changes.is_read)?;
+            let count = ids.len();
+            if count > 0 {
+                publish_or_log(&events, MailEvent::UpdatedMany {
+                    ids,
+                    is_read: changes.is_read,
+                });
+            }
+            Ok::<_, DbError>(count)
         })
-        .await
-        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        .await??;
+        Ok(Json(Updated { updated }))
+    }
 
-        let mut mail = mail_result?;
+    async fn delete_mail(self: Arc<Self>, id: i64) -> ApiResult<StatusCode> {
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let deleted =
+            tokio::task::spawn_blocking(move || delete_and_publish(&storage, &events, id)).await??;
+        if !deleted {
+            return Err(ApiError::MailNotFound(id));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
 
-        // Load attachments for this mail
-        mail.attachments = self.load_attachments_for_mail(id).await;
+    /// Fetch a mail without side effects, unless `mark_read` is set
+    async fn get_mail(self: Arc<Self>, id: i64, params: GetParams) -> ApiResult<Json<StoredMail>> {
+        if params.mark_read {
+            return self.update_mail(id, MailChanges { is_read: Some(true) }).await;
+        }
+        Ok(Json(self.load_mail(id).await?))
+    }
 
+    /// Change the flags of a mail, answering with its new state
+    async fn update_mail(self: Arc<Self>, id: i64, changes: MailChanges) -> ApiResult<Json<StoredMail>> {
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let mail = tokio::task::spawn_blocking(move || match changes.is_read {
+            Some(is_read) => set_read_and_publish(&storage, &events, id, is_read),
+            None => storage.get_mail(id),
+        })
+        .await??
+        .ok_or(ApiError::MailNotFound(id))?;
         Ok(Json(mail))
     }
+
+    /// List every header of a mail in message order
+    async fn get_mail_headers(self: Arc<Self>, id: i64) -> ApiResult<Json<Vec<MailHeader>>> {
+        let storage = self.storage.clone();
+        let headers = tokio::task::spawn_blocking(move || storage.mail_headers(id))
+            .await??
+            .ok_or(ApiError::MailNotFound(id))?;
+        Ok(Json(headers))
+    }
+
+    /// List the links of a mail, HTML anchors first, then URLs of the text body
+    async fn get_mail_links(self: Arc<Self>, id: i64) -> ApiResult<Json<Vec<ExtractedLink>>> {
+        let mail = self.load_mail(id).await?;
+        Ok(Json(extract::links(&mail)))
+    }
+
+    /// List the one-time codes of a mail, matched by `pattern` or by digit count
+    async fn get_mail_codes(self: Arc<Self>, id: i64, params: CodeParams) -> ApiResult<Json<Vec<String>>> {
+        let pattern = match params.pattern.as_deref() {
+            Some(pattern) => CodePattern::Regex(
+                regex::Regex::new(pattern)
+                    .map_err(|e| ApiError::BadRequest(format!("Invalid pattern: {}", e)))?,
+            ),
+            None => {
+                let (default_min, default_max) = extract::DEFAULT_CODE_LENGTHS;
+                let min = params.length.or(params.min_length).unwrap_or(default_min);
+                let max = params.length.or(params.max_length).unwrap_or(default_max.max(min));
+                if min == 0 || min > max {
+                    return Err(ApiError::BadRequest(format!(
+                        "Invalid code length range {}..={}",
+                        min, max
+                    )));
+                }
+                CodePattern::Digits { min, max }
+            }
+        };
+
+        let mail = self.load_mail(id).await?;
+        Ok(Json(extract::codes(&mail, &pattern)))
+    }
+
+    async fn list_webhooks(self: Arc<Self>) -> ApiResult<Json<Vec<Webhook>>> {
+        let storage = self.storage.clone();
+        let webhooks = tokio::task::spawn_blocking(move || storage.list_webhooks()).await??;
+        Ok(Json(webhooks))
+    }
+
+    /// Register a webhook target, answering 201 with the stored webhook
+    async fn create_webhook(self: Arc<Self>, params: WebhookParams) -> ApiResult<(StatusCode, Json<Webhook>)> {
+        if !(params.url.starts_with("http://") || params.url.starts_with("https://")) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid webhook URL `{}`, expected http(s)://",
+                params.url
+            )));
+        }
+        if let Some(event) = params.events.iter().find(|event| !webhooks::EVENTS.contains(&event.as_str())) {
+            return Err(ApiError::BadRequest(format!(
+                "Unknown event `{}`, expected one of {}",
+                event,
+                webhooks::EVENTS.join(", ")
+            )));
+        }
+
+        let storage = self.storage.clone();
+        let webhook = tokio::task::spawn_blocking(move || {
+            let secret = params.secret.as_deref().filter(|secret| !secret.is_empty());
+            storage.insert_webhook(&params.url, secret, &params.events)
+        })
+        .await??;
+        Ok((StatusCode::CREATED, Json(webhook)))
+    }
+
+    async fn delete_webhook(self: Arc<Self>, id: i64) -> ApiResult<StatusCode> {
+        let storage = self.storage.clone();
+        if !tokio::task::spawn_blocking(move || storage.delete_webhook(id)).await?? {
+            return Err(ApiError::WebhookNotFound(id));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Chaos rules in the order they are checked
+    fn list_chaos_rules(&self) -> Json<Vec<ChaosRule>> {
+        Json(self.chaos.rules())
+    }
+
+    /// Add a chaos rule, answering 201 with the stored rule
+    fn create_chaos_rule(&self, params: ChaosRuleParams) -> ApiResult<(StatusCode, Json<ChaosRule>)> {
+        if let Some(code) = params.code.filter(|code| !(400..600).contains(code)) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid reply code {}, expected 4xx or 5xx",
+                code
+            )));
+        }
+        if params.code.is_none() && params.delay_ms.is_none() && params.bytes_per_second.is_none() {
+            return Err(ApiError::BadRequest(
+                "A rule needs a `code`, a `delay_ms` or a `bytes_per_second`".to_string(),
+            ));
+        }
+        if params.bytes_per_second.is_some() && params.stage != ChaosStage::Data {
+            return Err(ApiError::BadRequest(
+                "`bytes_per_second` only applies to the `data` stage".to_string(),
+            ));
+        }
+        if params.bytes_per_second == Some(0) {
+            return Err(ApiError::BadRequest("`bytes_per_second` must be at least 1".to_string()));
+        }
+        if params.percent.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+            return Err(ApiError::BadRequest("`percent` must be between 0 and 100".to_string()));
+        }
+        if params.times == Some(0) {
+            return Err(ApiError::BadRequest("`times` must be at least 1".to_string()));
+        }
+
+        let message = params.code.map(|code| {
+            params.message.filter(|message| !message.is_empty()).unwrap_or_else(|| match code {
+                400..500 => "Simulated temporary failure".to_string(),
+                _ => "Simulated permanent failure".to_string(),
+            })
+        });
+        let rule = ChaosRule {
+            id: 0,
+            stage: params.stage,
+            code: params.code,
+            message,
+            recipient: params.recipient,
+            sender: params.sender,
+            percent: params.percent,
+            remaining: params.times,
+            delay_ms: params.delay_ms,
+            bytes_per_second: params.bytes_per_second,
+        };
+        let rule = self
+            .chaos
+            .add(rule)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid address pattern: {}", e)))?;
+        Ok((StatusCode::CREATED, Json(rule)))
+    }
+
+    fn delete_chaos_rule(&self, id: i64) -> ApiResult<StatusCode> {
+        if !self.chaos.remove(id) {
+            return Err(ApiError::ChaosRuleNotFound(id));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    fn get_latency(&self) -> Json<Latency> {
+        Json(self.chaos.latency())
+    }
+
+    /// Replace the latency of every SMTP session, answering the new settings
+    fn set_latency(&self, latency: Latency) -> ApiResult<Json<Latency>> {
+        if latency.bytes_per_second == Some(0) {
+            return Err(ApiError::BadRequest("`bytes_per_second` must be at least 1".to_string()));
+        }
+        self.chaos.set_latency(latency.clone());
+        Ok(Json(latency))
+    }
+
+    fn clear_chaos_rules(&self) -> Json<Deleted> {
+        Json(Deleted {
+            deleted: self.chaos.clear(),
+        })
+    }
+
+    /// Delivery attempts, most recent first
+    async fn list_deliveries(self: Arc<Self>, params: DeliveryParams) -> ApiResult<Json<Vec<WebhookDelivery>>> {
+        let storage = self.storage.clone();
+        let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let deliveries =
+            tokio::task::spawn_blocking(move || storage.list_deliveries(params.webhook_id, limit))
+                .await??;
+        Ok(Json(deliveries))
+    }
+
+    /// Serve the original RFC 5322 bytes, inline or as an `.eml` download
+    async fn get_raw_mail(self: Arc<Self>, id: i64, download: bool) -> ApiResult<impl IntoResponse> {
+        let storage = self.storage.clone();
+        let raw = tokio::task::spawn_blocking(move || {
+            let raw = storage.raw_mail(id)?;
+            // Tell a missing mail apart from one stored before sources were kept
+            let exists = raw.is_some() || storage.get_mail(id)?.is_some();
+            Ok::<_, DbError>((raw, exists))
+        })
+        .await??;
+        let raw = match raw {
+            (Some(raw), _) => raw,
+            (None, true) => {
+                return Err(ApiError::NotFound(format!("No source stored for mail {}", id)));
+            }
+            (None, false) => return Err(ApiError::MailNotFound(id)),
+        };
+
+        let disposition = if download {
+            format!("attachment; filename=\"mail-{}.eml\"", id)
+        } else {
+            "inline".to_string()
+        };
+        Ok((
+            [
+                (header::CONTENT_TYPE, "message/rfc822".to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            raw,
+        ))
+    }
+
+    /// Fetch a mail, `MailNotFound` when it does not exist
+    async fn load_mail(&self, id: i64) -> ApiResult<StoredMail> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.get_mail(id))
+            .await??
+            .ok_or(ApiError::MailNotFound(id))
+    }
+}
+
+/// Query parameters accepted by `GET /api/mails`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    /// Search query, see `query::parse` for the syntax
+    q: Option<String>,
+    /// Only mails with this recipient address (any kind unless `kind` is set)
+    recipient: Option<String>,
+    /// One of `to`, `cc`, `bcc` or `reply_to`; alone, mails with any recipient of that kind
+    kind: Option<String>,
+    /// Page size, `DEFAULT_PAGE_SIZE` when missing and at most `MAX_PAGE_SIZE`
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Filters accepted by `DELETE /api/mails` and `PATCH /api/mails`, as in `ListParams`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FilterParams {
+    q: Option<String>,
+    recipient: Option<String>,
+    kind: Option<String>,
+}
+
+/// Query parameters accepted by `GET /api/mails/:id`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GetParams {
+    /// Mark the mail read while fetching it, as the web UI does when opening it
+    #[serde(default)]
+    mark_read: bool,
+}
+
+/// Body accepted by `PATCH /api/mails/:id`, missing fields are left unchanged
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MailChanges {
+    is_read: Option<bool>,
+}
+
+/// Body accepted by `PATCH /api/mails`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BulkChanges {
+    is_read: bool,
+}
+
+/// Query parameters accepted by `GET /api/mails/wait`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WaitParams {
+    /// Search query, combined with the other parameters
+    q: Option<String>,
+    /// Substring of the sender name or address
+    from: Option<String>,
+    /// Substring of any To, Cc or Bcc name or address
+    to: Option<String>,
+    /// Substring of the subject
+    subject: Option<String>,
+    /// Only consider mails with a greater id, e.g. the newest id seen before the test started
+    after_id: Option<i64>,
+    /// How long to wait, e.g. `10s`, `500ms` or `2m`
+    timeout: Option<String>,
+}
+
+/// Query parameters accepted by `GET /api/mails/:id/codes`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CodeParams {
+    /// Regular expression, the first capture group (or the whole match) is the code
+    pattern: Option<String>,
+    /// Exact number of digits, shorthand for equal `min_length` and `max_length`
+    length: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+}
+
+/// Body accepted by `POST /api/webhooks`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct WebhookParams {
+    url: String,
+    /// Key used to sign deliveries with HMAC-SHA256, unsigned when missing
+    secret: Option<String>,
+    /// Events to deliver, see `webhooks::EVENTS`; every event when empty
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+/// Body accepted by `POST /api/chaos/rules`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChaosRuleParams {
+    stage: ChaosStage,
+    /// SMTP reply code, 4xx or 5xx. 421 also closes the connection.
+    /// Without it the mail goes on, once delayed or throttled.
+    code: Option<u16>,
+    /// Reply text, a generic one when missing
+    message: Option<String>,
+    /// Regular expression matched against recipient addresses, case-insensitively
+    recipient: Option<String>,
+    /// Regular expression matched against the sender address, case-insensitively
+    sender: Option<String>,
+    /// Chance in percent that a matching mail is refused, always when missing
+    percent: Option<f64>,
+    /// Only fire for this many matching mails, then remove the rule
+    times: Option<u32>,
+    /// Wait before answering at `stage`
+    delay_ms: Option<u64>,
+    /// Slow the upload of the content of matching mails, `data` stage only
+    bytes_per_second: Option<u64>,
+}
+
+/// Query parameters accepted by `GET /api/webhooks/deliveries`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveryParams {
+    webhook_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Answer of `DELETE /api/mails`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Deleted {
+    deleted: usize,
+}
+
+/// Answer of `PATCH /api/mails`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Updated {
+    updated: usize,
+}
+
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Filter for a search query combined with the `recipient` and `kind` parameters
+fn parse_filter(
+    q: Option<&str>,
+    recipient: Option<String>,
+    kind: Option<String>,
+) -> Result<MailFilter, ApiError> {
+    if let Some(kind) = kind.as_deref().filter(|kind| !RECIPIENT_KINDS.contains(kind)) {
+        return Err(ApiError::BadRequest(format!(
+            "Unknown recipient kind `{}`, expected one of {}",
+            kind,
+            RECIPIENT_KINDS.join(", ")
+        )));
+    }
+    let mut filter = match q {
+        Some(q) => query::parse(q)?,
+        None => MailFilter::default(),
+    };
+    filter.recipient = recipient;
+    filter.kind = kind;
+    Ok(filter)
 }
 
 async fn spa_fallback() -> Html<String> {
@@ -225,16 +779,105 @@ async fn spa_fallback() -> Html<String> {
     }
 }
 
+/// Tell subscribers about a change already made. Failing to is logged, not
+/// answered, so that clients are not told the change did not happen.
+fn publish_or_log(events: &EventBus, event: MailEvent) {
+    if let Err(e) = events.publish(event) {
+        eprintln!("[API] Failed to publish mail event: {}", e);
+    }
+}
+
+/// Delete a mail and publish `mail.deleted`, returning whether it existed.
+/// Blocks on the database.
+pub fn delete_and_publish(storage: &Storage, events: &EventBus, id: i64) -> Result<bool, DbError> {
+    // Keep the mail around to tell subscribers what went away
+    let Some(mail) = storage.get_mail(id)? else {
+        return Ok(false);
+    };
+    let deleted = storage.delete_mail(id)?;
+    if deleted {
+        publish_or_log(events, MailEvent::Deleted(mail));
+    }
+    Ok(deleted)
+}
+
+/// Mark a mail read or unread, publishing `mail.updated` when that changed
+/// something. Returns the mail in its new state, `None` if it does not exist.
+/// Blocks on the database.
+pub fn set_read_and_publish(
+    storage: &Storage,
+    events: &EventBus,
+    id: i64,
+    is_read: bool,
+) -> Result<Option<StoredMail>, DbError> {
+    let Some(mut mail) = storage.get_mail(id)? else {
+        return Ok(None);
+    };
+    if mail.is_read != is_read && storage.set_read(id, is_read)? {
+        mail.is_read = is_read;
+        publish_or_log(events, MailEvent::Updated(mail.clone()));
+    }
+    Ok(Some(mail))
+}
+
+/// Stream mail events as named SSE events. A client reconnecting with
+/// `Last-Event-ID` first gets what it missed, replayed from the event log. A
+/// `resync` event tells it to reload everything instead, when events were lost
+/// because it fell behind or its last event is no longer in the log.
 async fn sse_events(
-    sender: broadcast::Sender<StoredMail>,
+    events: EventBus,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
-    let mut rx = sender.subscribe();
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<i64>().ok());
+    // Subscribe before replaying so nothing published in between is missed
+    let mut rx = events.subscribe();
     let stream = async_stream! {
-        while let Ok(mail) = rx.recv().await {
-            let json = serde_json::to_string(&mail).unwrap();
-            yield Ok(Event::default().data(json));
+        let mut last_sent = 0;
+        if let Some(after_id) = last_event_id {
+            let replayed = tokio::task::spawn_blocking(move || events.replay(after_id)).await;
+            match replayed {
+                Ok(Ok(Some(replayed))) => {
+                    last_sent = after_id;
+                    for event in replayed {
+                        last_sent = event.id;
+                        yield Ok(sse_event(&event));
+                    }
+                }
+                Ok(Ok(None)) => yield Ok(resync_event()),
+                Ok(Err(e)) => {
+                    eprintln!("[SSE] Failed to replay events: {}", e);
+                    yield Ok(resync_event());
+                }
+                Err(e) => {
+                    eprintln!("[SSE] Task failed: {}", e);
+                    yield Ok(resync_event());
+                }
+            }
+        }
+        loop {
+            match rx.recv().await {
+                // Already replayed
+                Ok(published) if published.id <= last_sent => continue,
+                Ok(published) => yield Ok(sse_event(&published.to_wire())),
+                Err(broadcast::error::RecvError::Lagged(_)) => yield Ok(resync_event()),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
         }
     };
     Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
+fn sse_event(event: &WireEvent) -> Event {
+    Event::default()
+        .id(event.id.to_string())
+        .event(event.event)
+        .data(event.data.to_string())
+}
+
+/// Sent without an id, so that `Last-Event-ID` still points at the last real event
+fn resync_event() -> Event {
+    Event::default().event("resync").data("{}")
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+/// Credentials accepted by SMTP AUTH. Once AUTH is offered, mailin requires
+/// clients to authenticate before sending mail, which is why it is off by default.
+#[derive(Debug, Clone, Default)]
+pub enum SmtpAuth {
+    /// AUTH is not offered, any client may send mail
+    #[default]
+    Disabled,
+    /// Any username and password are accepted
+    AnyCredentials,
+    /// Only these passwords, by username
+    Users(HashMap<String, String>),
+}
+
+impl SmtpAuth {
+    /// Parse a `user:password` list separated by commas, e.g. `alice:secret,bob:hunter2`
+    pub fn users(list: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once(':') {
+                Some((username, password)) if !username.is_empty() => {
+                    users.insert(username.to_string(), password.to_string());
+                }
+                _ => return Err(format!("Invalid credentials `{}`, expected user:password", entry)),
+            }
+        }
+        if users.is_empty() {
+            return Err("No credentials given".to_string());
+        }
+        Ok(SmtpAuth::Users(users))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, SmtpAuth::Disabled)
+    }
+
+    pub fn accepts(&self, username: &str, password: &str) -> bool {
+        match self {
+            SmtpAuth::Disabled => false,
+            SmtpAuth::AnyCredentials => true,
+            SmtpAuth::Users(users) => users.get(username).is_some_and(|expected| expected == password),
+        }
+    }
+}
@@ -1,36 +1,336 @@
-use crate::mail_handler::MailinHandler;
-use crate::models::StoredMail;
-use mailin_embedded::Server;
-use std::net::TcpListener;
-use tokio::{sync::broadcast, task};
+use crate::chaos::{self, Chaos};
+use crate::events::EventBus;
+use crate::mail_handler::{MailinHandler, Refusal};
+use crate::models::TlsInfo;
+use crate::smtp_auth::SmtpAuth;
+use crate::storage::Storage;
+use crate::tls;
+use mailin::{Action, AuthMechanism, Response, Session, SessionBuilder};
+use rustls::ServerConfig;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tokio::task;
+
+/// Name announced in the greeting and EHLO reply
+const SERVER_NAME: &str = "example.com";
+/// Idle clients are disconnected after this long
+const SESSION_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub struct SmtpServer {
-    db_path: String,
-    sender: broadcast::Sender<StoredMail>,
+    storage: Storage,
+    events: EventBus,
+    auth: Arc<SmtpAuth>,
+    tls: Option<Arc<ServerConfig>>,
+    starttls: bool,
+    chaos: Arc<Chaos>,
+}
+
+/// How a listener secures its connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    /// Plaintext, upgraded when the client asks for STARTTLS
+    Plain,
+    /// TLS from the first byte, as on port 465
+    ImplicitTls,
 }
 
 impl SmtpServer {
-    pub fn new(db_path: String, sender: broadcast::Sender<StoredMail>) -> Self {
-        Self { db_path, sender }
+    pub fn new(storage: Storage, events: EventBus) -> Self {
+        Self {
+            storage,
+            events,
+            auth: Arc::default(),
+            tls: None,
+            starttls: true,
+            chaos: Arc::default(),
+        }
+    }
+
+    /// Offer AUTH PLAIN and LOGIN, and require it before accepting mail
+    pub fn with_auth(mut self, auth: SmtpAuth) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Certificate for STARTTLS and the SMTPS listener
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
     }
 
-    pub async fn run(&self) {
-        let db_path = self.db_path.clone();
-        let sender = self.sender.clone();
+    /// Whether the plain listener offers STARTTLS when a certificate is set, on by default
+    pub fn with_starttls(mut self, enabled: bool) -> Self {
+        self.starttls = enabled;
+        self
+    }
+
+    /// Inject the failures described by the rules in `chaos`
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Listen on `SMTP_PORT`, and with implicit TLS on `SMTPS_PORT` when set
+    pub async fn run(self) {
+        let this = Arc::new(self);
         let smtp_port = std::env::var("SMTP_PORT").unwrap_or_else(|_| "1025".to_string());
-        task::spawn_blocking(move || {
-            let handler = MailinHandler::new(db_path, sender);
-            let mut server = Server::new(handler);
-            let bind_addr = format!("0.0.0.0:{}", smtp_port);
-            let listener = TcpListener::bind(&bind_addr).unwrap();
-            server.with_name("example.com").with_tcp_listener(listener);
-            println!("SMTP server listening on {}", bind_addr);
-            if let Err(e) = server.serve() {
-                eprintln!("Error: {}", e);
+        let plain = Arc::clone(&this).listen(format!("0.0.0.0:{}", smtp_port), Transport::Plain);
+        let smtps = async {
+            let Ok(smtps_port) = std::env::var("SMTPS_PORT") else {
+                return;
+            };
+            if this.tls.is_none() {
+                eprintln!("[SMTP] SMTPS_PORT is set but no TLS certificate is configured");
+                return;
             }
+            Arc::clone(&this)
+                .listen(format!("0.0.0.0:{}", smtps_port), Transport::ImplicitTls)
+                .await;
+        };
+        tokio::join!(plain, smtps);
+    }
+
+    async fn listen(self: Arc<Self>, bind_addr: String, transport: Transport) {
+        let served = task::spawn_blocking(move || {
+            let listener = TcpListener::bind(&bind_addr)?;
+            match transport {
+                Transport::Plain => {
+                    println!("SMTP server listening on {}", bind_addr);
+                    self.serve(listener);
+                }
+                Transport::ImplicitTls => {
+                    println!("SMTPS server listening on {}", bind_addr);
+                    self.serve_tls(listener);
+                }
+            }
+            Ok::<_, io::Error>(())
         })
-        .await
-        .unwrap();
+        .await;
+        match served {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("[SMTP] Failed to listen: {}", e),
+            Err(e) => eprintln!("[SMTP] Task failed: {}", e),
+        }
     }
+
+    /// Accept plaintext connections forever, serving each one from its own thread. Blocks.
+    pub fn serve(&self, listener: TcpListener) {
+        self.accept(listener, Transport::Plain);
+    }
+
+    /// Accept implicit TLS connections forever, like `serve`. Needs a certificate. Blocks.
+    pub fn serve_tls(&self, listener: TcpListener) {
+        self.accept(listener, Transport::ImplicitTls);
+    }
+
+    fn accept(&self, listener: TcpListener, transport: Transport) {
+        let handler = MailinHandler::new(self.storage.clone(), self.events.clone())
+            .with_auth(Arc::clone(&self.auth))
+            .with_chaos(Arc::clone(&self.chaos));
+        let sessions = self.session_builder(transport);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("[SMTP] Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let sessions = sessions.clone();
+            let handler = handler.clone();
+            let tls = self.tls.clone();
+            let chaos = Arc::clone(&self.chaos);
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &sessions, handler, tls, chaos, transport) {
+                    eprintln!("[SMTP] Session ended with an error: {}", e);
+                }
+            });
+        }
+    }
+
+    fn session_builder(&self, transport: Transport) -> SessionBuilder {
+        let mut sessions = SessionBuilder::new(SERVER_NAME);
+        if transport == Transport::Plain && self.starttls && self.tls.is_some() {
+            sessions.enable_start_tls();
+        }
+        if self.auth.is_enabled() {
+            // Credentials are fake, clients must be able to log in without STARTTLS too
+            sessions
+                .enable_auth(AuthMechanism::Plain)
+                .enable_auth(AuthMechanism::Login)
+                .insecure_enable_plaintext_auth();
+        }
+        sessions
+    }
+}
+
+/// How a conversation with the client ended
+enum SessionEnd {
+    Closed,
+    /// The client asked for STARTTLS, which was accepted
+    UpgradeTls,
+}
+
+/// Run one SMTP session until the client quits or disconnects
+fn handle_connection(
+    stream: TcpStream,
+    sessions: &SessionBuilder,
+    handler: MailinHandler,
+    tls: Option<Arc<ServerConfig>>,
+    chaos: Arc<Chaos>,
+    transport: Transport,
+) -> io::Result<()> {
+    let remote = stream.peer_addr()?.ip();
+    stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+    stream.set_write_timeout(Some(SESSION_TIMEOUT))?;
+
+    let negotiated = Arc::new(OnceLock::new());
+    let refusal = Refusal::default();
+    let handler = handler
+        .with_tls(Arc::clone(&negotiated))
+        .with_refusal(Arc::clone(&refusal));
+    let mut session = sessions.build(remote, handler);
+    if transport == Transport::ImplicitTls {
+        let Some(config) = tls else {
+            return Ok(());
+        };
+        return converse_securely(&mut session, config, stream, &negotiated, &refusal, &chaos, true);
+    }
+
+    let mut plain = BufReader::new(Throttled::new(stream, Arc::clone(&chaos)));
+    greet(plain.get_mut(), &session, &chaos)?;
+    let (SessionEnd::UpgradeTls, Some(config)) = (converse(&mut session, &mut plain, &refusal, &chaos)?, tls) else {
+        return Ok(());
+    };
+    // Anything the client sent before the handshake is dropped, as RFC 3207 requires
+    let stream = plain.into_inner().into_inner();
+    converse_securely(&mut session, config, stream, &negotiated, &refusal, &chaos, false)
 }
 
+/// Run the TLS handshake, then the rest of the session over it, greeting the
+/// client first unless it already was before STARTTLS
+fn converse_securely(
+    session: &mut Session<MailinHandler>,
+    config: Arc<ServerConfig>,
+    stream: TcpStream,
+    negotiated: &OnceLock<TlsInfo>,
+    refusal: &Refusal,
+    chaos: &Arc<Chaos>,
+    greet_first: bool,
+) -> io::Result<()> {
+    let (stream, info) = tls::accept(config, stream)?;
+    if let Some(info) = info {
+        let _ = negotiated.set(info);
+    }
+    session.tls_active();
+    let mut secure = BufReader::new(Throttled::new(stream, Arc::clone(chaos)));
+    if greet_first {
+        greet(secure.get_mut(), session, chaos)?;
+    }
+    converse(session, &mut secure, refusal, chaos)?;
+    Ok(())
+}
+
+fn greet(writer: &mut impl Write, session: &Session<MailinHandler>, chaos: &Chaos) -> io::Result<()> {
+    thread::sleep(Duration::from_millis(chaos.latency().greeting_ms));
+    write_response(writer, &session.greeting())
+}
+
+/// Answer the client's commands until it leaves or switches to TLS
+fn converse<S: Read + Write>(
+    session: &mut Session<MailinHandler>,
+    stream: &mut BufReader<S>,
+    refusal: &Refusal,
+    chaos: &Chaos,
+) -> io::Result<SessionEnd> {
+    let mut line = Vec::new();
+    // Whether the client is sending the content, the next reply ends the data
+    let mut in_data = false;
+    loop {
+        line.clear();
+        match stream.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(SessionEnd::Closed),
+            Ok(_) => {}
+            // TLS clients often hang up without a close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(SessionEnd::Closed),
+            Err(e) => return Err(e),
+        }
+        let mut response = session.process(&line);
+        // The handler accepted the end of data to close the transaction, but
+        // may have refused the message
+        if let Some(refused) = refusal.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            response = refused;
+        }
+        if response.action == Action::NoReply {
+            continue;
+        }
+
+        let latency = chaos.latency();
+        let delay = if in_data { latency.data_end_ms } else { latency.command_ms };
+        thread::sleep(Duration::from_millis(delay));
+        in_data = response.code == 354;
+        write_response(stream.get_mut(), &response)?;
+        match response.action {
+            Action::Close => return Ok(SessionEnd::Closed),
+            Action::UpgradeTls => return Ok(SessionEnd::UpgradeTls),
+            Action::Reply | Action::NoReply => {}
+        }
+    }
+}
+
+/// Stream limited to the bandwidth of the current `Latency`, in each direction
+struct Throttled<S> {
+    inner: S,
+    chaos: Arc<Chaos>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, chaos: Arc<Chaos>) -> Self {
+        Self { inner, chaos }
+    }
+
+    fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Bytes moved between two waits, a tenth of a second's worth to keep the rate smooth
+fn throttle_chunk(bytes_per_second: u64, len: usize) -> usize {
+    ((bytes_per_second / 10).max(1) as usize).min(len)
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(bytes_per_second) = self.chaos.latency().bytes_per_second else {
+            return self.inner.read(buf);
+        };
+        let chunk = throttle_chunk(bytes_per_second, buf.len());
+        let read = self.inner.read(&mut buf[..chunk])?;
+        chaos::throttle(read, bytes_per_second);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(bytes_per_second) = self.chaos.latency().bytes_per_second else {
+            return self.inner.write(buf);
+        };
+        let chunk = throttle_chunk(bytes_per_second, buf.len());
+        let written = self.inner.write(&buf[..chunk])?;
+        chaos::throttle(written, bytes_per_second);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    response.write_to(writer)?;
+    writer.flush()
+}
@@ -0,0 +1,870 @@
+use crate::db::{DbError, init_db};
+use crate::html;
+use crate::models::{
+    Attachment, Envelope, LoggedEvent, MailHeader, MailPage, MailSummary, Recipient, StoredMail,
+    TlsInfo, Webhook, WebhookDelivery,
+};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, params_from_iter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Connections kept open by the pool, shared by the SMTP and REST sides
+const POOL_SIZE: u32 = 8;
+/// How long a writer waits on a locked database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Prepared statements cached per connection
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+/// Files younger than this are never collected, their row may not be committed yet
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+/// Characters of the text body included in a `MailSummary`
+const PREVIEW_LENGTH: i64 = 200;
+/// Prefix of `Attachment::file_url`, mapped onto the attachments directory
+const ATTACHMENTS_URL_PREFIX: &str = "/attachments/";
+
+/// Webhook delivery attempts kept in the log, older ones are pruned
+const DELIVERY_LOG_SIZE: i64 = 1000;
+
+/// Published events kept for replay, older ones are pruned
+const EVENT_LOG_SIZE: i64 = 10_000;
+
+/// Columns read by `mail_from_row`, in order
+const MAIL_COLUMNS: &str = "id, from_address, from_name, to_address, to_name, subject, html, text, date, is_read, parse_warnings, helo_domain, envelope_from, envelope_to, is_8bit, auth_user, tls_protocol, tls_cipher";
+
+/// Pooled access to the mail database. Cheap to clone.
+#[derive(Clone)]
+pub struct Storage {
+    pool: Pool<SqliteConnectionManager>,
+    attachments_dir: PathBuf,
+}
+
+/// Values of `recipients.kind`, one per address header
+pub const RECIPIENT_KINDS: &[&str] = &["to", "cc", "bcc", "reply_to"];
+
+/// Filters accepted by `Storage::list_summaries`, all of them must match
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MailFilter {
+    /// Only mails with this exact recipient address (any kind unless `kind` is set)
+    pub recipient: Option<String>,
+    /// One of `RECIPIENT_KINDS`. Alone, only mails with a recipient of this kind.
+    pub kind: Option<String>,
+    /// Substrings of the sender name or address
+    pub from: Vec<String>,
+    /// Substrings of any To, Cc or Bcc recipient name or address
+    pub to: Vec<String>,
+    /// Substrings of the subject
+    pub subject: Vec<String>,
+    /// Free text terms, searched in the full-text index
+    pub text: Vec<String>,
+    pub is_read: Option<bool>,
+    pub has_attachment: Option<bool>,
+    /// Dates understood by SQLite's `julianday`
+    pub before: Option<String>,
+    pub after: Option<String>,
+    /// Only mails stored after the one with this id
+    pub after_id: Option<i64>,
+}
+
+impl Storage {
+    /// Migrate the database at `db_path` and open a connection pool on it
+    pub fn open(db_path: &str) -> Result<Self, DbError> {
+        let conn = init_db(db_path)?;
+        // WAL is persistent, so switching once is enough
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        backfill_search_index(&conn)?;
+        drop(conn);
+
+        let manager = SqliteConnectionManager::file(db_path).with_init(|conn| {
+            // Off by default in SQLite, needed for ON DELETE CASCADE
+            conn.pragma_update(None, "foreign_keys", true)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+            conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+            Ok(())
+        });
+        let pool = Pool::builder().max_size(POOL_SIZE).build(manager)?;
+        Ok(Self {
+            pool,
+            attachments_dir: PathBuf::from("./attachments"),
+        })
+    }
+
+    /// Store attachment files somewhere else than `./attachments`
+    pub fn with_attachments_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.attachments_dir = dir.into();
+        self
+    }
+
+    pub fn attachments_dir(&self) -> &Path {
+        &self.attachments_dir
+    }
+
+    pub fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>, DbError> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Insert a mail with its recipients and headers, setting `mail.id`
+    pub fn insert_mail(
+        &self,
+        mail: &mut StoredMail,
+        raw: &[u8],
+        headers: &[MailHeader],
+    ) -> Result<(), DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let warnings_json = serde_json::to_string(&mail.parse_warnings).unwrap_or_default();
+        let rcpt_json = serde_json::to_string(&mail.envelope.rcpt_to).unwrap_or_default();
+
+        tx.prepare_cached(
+            "INSERT INTO mails (from_address, from_name, to_address, to_name, subject, html, text, date, is_read, raw, parse_warnings, helo_domain, envelope_from, envelope_to, is_8bit, auth_user, tls_protocol, tls_cipher) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?
+        .execute(rusqlite::params![
+            mail.from_address,
+            mail.from_name,
+            mail.to_address,
+            mail.to_name,
+            mail.subject,
+            mail.html,
+            mail.text,
+            mail.date,
+            mail.is_read,
+            raw,
+            warnings_json,
+            mail.envelope.helo_domain,
+            mail.envelope.mail_from,
+            rcpt_json,
+            mail.envelope.is_8bit,
+            mail.envelope.auth_user,
+            mail.envelope.tls.as_ref().map(|tls| &tls.protocol),
+            mail.envelope.tls.as_ref().map(|tls| &tls.cipher),
+        ])?;
+        let mail_id = tx.last_insert_rowid();
+
+        {
+            let mut insert_header = tx.prepare_cached(
+                "INSERT INTO headers (mail_id, position, name, raw_value, value) VALUES (?, ?, ?, ?, ?)",
+            )?;
+            for (position, header) in headers.iter().enumerate() {
+                insert_header.execute(rusqlite::params![
+                    mail_id,
+                    position as i64,
+                    header.name,
+                    header.raw_value,
+                    header.value
+                ])?;
+            }
+
+            let mut insert_recipient = tx.prepare_cached(
+                "INSERT INTO recipients (mail_id, kind, name, address) VALUES (?, ?, ?, ?)",
+            )?;
+            for (kind, recipients) in RECIPIENT_KINDS
+                .iter()
+                .zip([&mail.to, &mail.cc, &mail.bcc, &mail.reply_to])
+            {
+                for recipient in recipients {
+                    insert_recipient.execute(rusqlite::params![
+                        mail_id,
+                        kind,
+                        recipient.name,
+                        recipient.address
+                    ])?;
+                }
+            }
+        }
+
+        mail.id = mail_id;
+        index_mail(&tx, mail)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record an attachment already written to disk, setting `attachment.id`
+    pub fn insert_attachment(&self, attachment: &mut Attachment) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.prepare_cached(
+            "INSERT INTO attachments (mail_id, filename, content_type, content_disposition, size_bytes, file_url) VALUES (?, ?, ?, ?, ?, ?)",
+        )?
+        .execute(rusqlite::params![
+            attachment.mail_id,
+            attachment.filename,
+            attachment.content_type,
+            attachment.content_disposition,
+            attachment.size_bytes,
+            attachment.file_url
+        ])?;
+        attachment.id = conn.last_insert_rowid();
+        conn.prepare_cached(
+            "UPDATE mails_fts SET attachments = trim(attachments || ' ' || ?) WHERE rowid = ?",
+        )?
+        .execute(rusqlite::params![attachment.filename, attachment.mail_id])?;
+        Ok(())
+    }
+
+    /// One page of mail summaries, newest first or by relevance when the
+    /// filter has free text, in which case each summary carries a snippet
+    pub fn list_summaries(
+        &self,
+        filter: &MailFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<MailPage, DbError> {
+        let conn = self.conn()?;
+        let sql = filter_sql(filter);
+
+        let (total, unread) = conn
+            .prepare_cached(&format!(
+                "SELECT COUNT(*), COALESCE(SUM(mails.is_read = 0), 0) FROM {} {}",
+                sql.source, sql.where_clause
+            ))?
+            .query_row(params_from_iter(&sql.params), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+        let (snippet, order) = if sql.searching {
+            (
+                "snippet(mails_fts, -1, '<mark>', '</mark>', '…', 12)",
+                "bm25(mails_fts), mails.date DESC",
+            )
+        } else {
+            ("NULL", "mails.date DESC, mails.id DESC")
+        };
+        // Placeholders in order: preview length, filters, then paging
+        let mut page_params = vec![Value::Integer(PREVIEW_LENGTH)];
+        page_params.extend(sql.params);
+        page_params.push(Value::Integer(limit));
+        page_params.push(Value::Integer(offset));
+        let items = conn
+            .prepare_cached(&format!(
+                "SELECT mails.id, mails.from_address, mails.from_name, mails.to_address, mails.to_name,
+                        mails.subject, substr(mails.text, 1, ?) AS preview, mails.date, mails.is_read,
+                        (SELECT COUNT(*) FROM attachments a WHERE a.mail_id = mails.id),
+                        {}
+                 FROM {} {}
+                 ORDER BY {}
+                 LIMIT ? OFFSET ?",
+                snippet, sql.source, sql.where_clause, order
+            ))?
+            .query_map(
+                params_from_iter(page_params),
+                |row| {
+                    Ok(MailSummary {
+                        id: row.get(0)?,
+                        from_address: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                        from_name: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                        to_address: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                        to_name: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                        subject: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                        preview: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                        date: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                        is_read: row.get::<_, i64>(8)? != 0,
+                        attachment_count: row.get(9)?,
+                        snippet: row.get(10)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(MailPage {
+            items,
+            total,
+            unread,
+            limit,
+            offset,
+        })
+    }
+
+    /// The earliest stored mail matching `filter`, in arrival order
+    pub fn first_matching(&self, filter: &MailFilter) -> Result<Option<StoredMail>, DbError> {
+        let conn = self.conn()?;
+        let sql = filter_sql(filter);
+        let id = conn
+            .prepare_cached(&format!(
+                "SELECT mails.id FROM {} {} ORDER BY mails.id LIMIT 1",
+                sql.source, sql.where_clause
+            ))?
+            .query_row(params_from_iter(&sql.params), |row| row.get(0))
+            .optional()?;
+        match id {
+            Some(id) => load_mail(&conn, id),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_mail(&self, id: i64) -> Result<Option<StoredMail>, DbError> {
+        let conn = self.conn()?;
+        load_mail(&conn, id)
+    }
+
+    pub fn set_read(&self, id: i64, is_read: bool) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let updated = conn
+            .prepare_cached("UPDATE mails SET is_read = ? WHERE id = ?")?
+            .execute(rusqlite::params![is_read, id])?;
+        Ok(updated > 0)
+    }
+
+    /// Delete a mail with its attachment rows and files, returning whether it existed
+    pub fn delete_mail(&self, id: i64) -> Result<bool, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let file_urls = tx
+            .prepare_cached("SELECT file_url FROM attachments WHERE mail_id = ?")?
+            .query_map([id], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM mails WHERE id = ?")?
+            .execute([id])?;
+        tx.commit()?;
+
+        // Only touch the disk once the rows are gone for good
+        for file_url in file_urls {
+            self.remove_attachment_file(&file_url);
+        }
+        Ok(deleted > 0)
+    }
+
+    /// Delete every mail matching `filter` with their attachment rows and files,
+    /// returning the deleted mails as they were
+    pub fn delete_matching(&self, filter: &MailFilter) -> Result<Vec<StoredMail>, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let sql = filter_sql(filter);
+        let ids = tx
+            .prepare(&format!("SELECT mails.id FROM {} {}", sql.source, sql.where_clause))?
+            .query_map(params_from_iter(&sql.params), |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut deleted = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(mail) = load_mail(&tx, id)? {
+                tx.prepare_cached("DELETE FROM mails WHERE id = ?")?
+                    .execute([id])?;
+                deleted.push(mail);
+            }
+        }
+        tx.commit()?;
+
+        for attachment in deleted.iter().flat_map(|mail| &mail.attachments) {
+            self.remove_attachment_file(&attachment.file_url);
+        }
+        Ok(deleted)
+    }
+
+    /// Delete every mail with their attachment rows and files, returning how many there were
+    pub fn clear_mails(&self) -> Result<usize, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let file_urls = tx
+            .prepare_cached("SELECT file_url FROM attachments")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let deleted = tx.prepare_cached("DELETE FROM mails")?.execute([])?;
+        tx.commit()?;
+
+        for file_url in file_urls {
+            self.remove_attachment_file(&file_url);
+        }
+        Ok(deleted)
+    }
+
+    /// Mark every mail matching `filter` read or unread, returning the ids of
+    /// those that changed
+    pub fn set_read_matching(&self, filter: &MailFilter, is_read: bool) -> Result<Vec<i64>, DbError> {
+        let conn = self.conn()?;
+        let sql = filter_sql(filter);
+        let mut params = vec![Value::Integer(is_read as i64)];
+        params.extend(sql.params);
+        params.push(Value::Integer(is_read as i64));
+        let ids = conn
+            .prepare(&format!(
+                "UPDATE mails SET is_read = ?
+                 WHERE id IN (SELECT mails.id FROM {} {}) AND is_read != ?
+                 RETURNING id",
+                sql.source, sql.where_clause
+            ))?
+            .query_map(params_from_iter(params), |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Remove files in the attachments directory that no attachment row points to
+    pub fn collect_orphaned_files(&self) -> Result<usize, DbError> {
+        let entries = match fs::read_dir(&self.attachments_dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(0),
+        };
+        let conn = self.conn()?;
+        let mut is_referenced =
+            conn.prepare_cached("SELECT 1 FROM attachments WHERE file_url = ?")?;
+        let mut removed = 0;
+
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let recent = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_none_or(|age| age < ORPHAN_GRACE_PERIOD);
+            if !metadata.is_file() || recent {
+                continue;
+            }
+            let file_url = format!(
+                "{}{}",
+                ATTACHMENTS_URL_PREFIX,
+                entry.file_name().to_string_lossy()
+            );
+            if !is_referenced.exists([&file_url])? && fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn remove_attachment_file(&self, file_url: &str) {
+        let Some(name) = file_url.strip_prefix(ATTACHMENTS_URL_PREFIX) else {
+            return;
+        };
+        let path = self.attachments_dir.join(name);
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            eprintln!("Failed to remove attachment {}: {}", path.display(), e);
+        }
+    }
+
+    /// Headers of a mail in message order, `None` if the mail does not exist
+    pub fn mail_headers(&self, id: i64) -> Result<Option<Vec<MailHeader>>, DbError> {
+        let conn = self.conn()?;
+        if !mail_exists(&conn, id)? {
+            return Ok(None);
+        }
+        let headers = conn
+            .prepare_cached(
+                "SELECT name, raw_value, value FROM headers WHERE mail_id = ? ORDER BY position",
+            )?
+            .query_map([id], |row| {
+                Ok(MailHeader {
+                    name: row.get(0)?,
+                    raw_value: row.get(1)?,
+                    value: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(headers))
+    }
+
+    /// Original message bytes, `None` if the mail or its source is missing
+    pub fn raw_mail(&self, id: i64) -> Result<Option<Vec<u8>>, DbError> {
+        let conn = self.conn()?;
+        let raw = conn
+            .prepare_cached("SELECT raw FROM mails WHERE id = ?")?
+            .query_row([id], |row| row.get::<_, Option<Vec<u8>>>(0))
+            .optional()?;
+        Ok(raw.flatten())
+    }
+
+    /// Log a published event, returning its id
+    pub fn record_event(&self, name: &str, mail_id: Option<i64>) -> Result<i64, DbError> {
+        let conn = self.conn()?;
+        let id = conn
+            .prepare_cached("INSERT INTO events (name, mail_id) VALUES (?, ?) RETURNING id")?
+            .query_row(rusqlite::params![name, mail_id], |row| row.get(0))?;
+        conn.prepare_cached("DELETE FROM events WHERE id <= ?")?
+            .execute([id - EVENT_LOG_SIZE])?;
+        Ok(id)
+    }
+
+    /// Events logged after `after_id`, oldest first.
+    /// `None` if some of them were already pruned, or if `after_id` was never
+    /// handed out by this database.
+    pub fn events_after(
+        &self,
+        after_id: i64,
+    ) -> Result<Option<Vec<LoggedEvent>>, DbError> {
+        let conn = self.conn()?;
+        let (oldest, newest): (Option<i64>, Option<i64>) = conn
+            .prepare_cached("SELECT MIN(id), MAX(id) FROM events")?
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let complete = match (oldest, newest) {
+            (Some(oldest), Some(newest)) => after_id >= oldest - 1 && after_id <= newest,
+            _ => after_id == 0,
+        };
+        if !complete {
+            return Ok(None);
+        }
+        let events = conn
+            .prepare_cached("SELECT id, name, mail_id FROM events WHERE id > ? ORDER BY id")?
+            .query_map([after_id], |row| {
+                Ok(LoggedEvent {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    mail_id: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(events))
+    }
+
+    pub fn list_webhooks(&self) -> Result<Vec<Webhook>, DbError> {
+        let conn = self.conn()?;
+        let webhooks = conn
+            .prepare_cached("SELECT id, url, secret, events, created_at FROM webhooks ORDER BY id")?
+            .query_map([], webhook_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(webhooks)
+    }
+
+    /// Register a webhook target, `events` empty meaning every event
+    pub fn insert_webhook(
+        &self,
+        url: &str,
+        secret: Option<&str>,
+        events: &[String],
+    ) -> Result<Webhook, DbError> {
+        let conn = self.conn()?;
+        let events_json = serde_json::to_string(events).unwrap_or_default();
+        let webhook = conn
+            .prepare_cached(
+                "INSERT INTO webhooks (url, secret, events) VALUES (?, ?, ?) RETURNING id, url, secret, events, created_at",
+            )?
+            .query_row(rusqlite::params![url, secret, events_json], webhook_from_row)?;
+        Ok(webhook)
+    }
+
+    /// Remove a webhook target, returning whether it existed. Its deliveries stay logged.
+    pub fn delete_webhook(&self, id: i64) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn
+            .prepare_cached("DELETE FROM webhooks WHERE id = ?")?
+            .execute([id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Log a delivery attempt, setting `delivery.id` and `delivery.delivered_at`
+    pub fn record_delivery(&self, delivery: &mut WebhookDelivery) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        let (id, delivered_at) = conn
+            .prepare_cached(
+                "INSERT INTO webhook_deliveries (webhook_id, url, event, mail_id, attempt, status, error, duration_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING id, delivered_at",
+            )?
+            .query_row(
+                rusqlite::params![
+                    delivery.webhook_id,
+                    delivery.url,
+                    delivery.event,
+                    delivery.mail_id,
+                    delivery.attempt,
+                    delivery.status,
+                    delivery.error,
+                    delivery.duration_ms
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+        delivery.id = id;
+        delivery.delivered_at = delivered_at;
+        conn.prepare_cached("DELETE FROM webhook_deliveries WHERE id <= ?")?
+            .execute([id - DELIVERY_LOG_SIZE])?;
+        Ok(())
+    }
+
+    /// Most recent delivery attempts first, optionally for one webhook only
+    pub fn list_deliveries(
+        &self,
+        webhook_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, DbError> {
+        let conn = self.conn()?;
+        let deliveries = conn
+            .prepare_cached(
+                "SELECT id, webhook_id, url, event, mail_id, attempt, status, error, duration_ms, delivered_at
+                 FROM webhook_deliveries
+                 WHERE ?1 IS NULL OR webhook_id = ?1
+                 ORDER BY id DESC
+                 LIMIT ?2",
+            )?
+            .query_map(rusqlite::params![webhook_id, limit], |row| {
+                Ok(WebhookDelivery {
+                    id: row.get(0)?,
+                    webhook_id: row.get(1)?,
+                    url: row.get(2)?,
+                    event: row.get(3)?,
+                    mail_id: row.get(4)?,
+                    attempt: row.get(5)?,
+                    status: row.get(6)?,
+                    error: row.get(7)?,
+                    duration_ms: row.get(8)?,
+                    delivered_at: row.get(9)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(deliveries)
+    }
+}
+
+/// SQL fragments for a filter: the `FROM` source, the `WHERE` clause (possibly
+/// empty) and its parameters. Free text joins the full-text index so callers can
+/// rank by `bm25(mails_fts)` and build snippets.
+struct FilterSql {
+    source: &'static str,
+    where_clause: String,
+    params: Vec<Value>,
+    searching: bool,
+}
+
+fn filter_sql(filter: &MailFilter) -> FilterSql {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+
+    if filter.recipient.is_some() || filter.kind.is_some() {
+        let mut condition = String::from("EXISTS (SELECT 1 FROM recipients r WHERE r.mail_id = mails.id");
+        if let Some(recipient) = &filter.recipient {
+            condition.push_str(" AND r.address = ? COLLATE NOCASE");
+            params.push(Value::Text(recipient.clone()));
+        }
+        if let Some(kind) = &filter.kind {
+            condition.push_str(" AND r.kind = ?");
+            params.push(Value::Text(kind.clone()));
+        }
+        condition.push(')');
+        conditions.push(condition);
+    }
+    for from in &filter.from {
+        conditions.push(
+            "(mails.from_address LIKE ? ESCAPE '\\' OR mails.from_name LIKE ? ESCAPE '\\')".to_string(),
+        );
+        params.push(like_pattern(from));
+        params.push(like_pattern(from));
+    }
+    for to in &filter.to {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM recipients r WHERE r.mail_id = mails.id AND r.kind IN ('to', 'cc', 'bcc') AND (r.address LIKE ? ESCAPE '\\' OR r.name LIKE ? ESCAPE '\\'))"
+                .to_string(),
+        );
+        params.push(like_pattern(to));
+        params.push(like_pattern(to));
+    }
+    for subject in &filter.subject {
+        conditions.push("mails.subject LIKE ? ESCAPE '\\'".to_string());
+        params.push(like_pattern(subject));
+    }
+    let searching = !filter.text.is_empty();
+    if searching {
+        conditions.push("mails_fts MATCH ?".to_string());
+        params.push(Value::Text(fts_query(&filter.text)));
+    }
+    if let Some(is_read) = filter.is_read {
+        conditions.push("mails.is_read = ?".to_string());
+        params.push(Value::Integer(is_read as i64));
+    }
+    if let Some(has_attachment) = filter.has_attachment {
+        let exists = "EXISTS (SELECT 1 FROM attachments a WHERE a.mail_id = mails.id)";
+        conditions.push(if has_attachment {
+            exists.to_string()
+        } else {
+            format!("NOT {}", exists)
+        });
+    }
+    if let Some(before) = &filter.before {
+        conditions.push("julianday(mails.date) < julianday(?)".to_string());
+        params.push(Value::Text(before.clone()));
+    }
+    if let Some(after) = &filter.after {
+        conditions.push("julianday(mails.date) >= julianday(?)".to_string());
+        params.push(Value::Text(after.clone()));
+    }
+
+    if let Some(after_id) = filter.after_id {
+        conditions.push("mails.id > ?".to_string());
+        params.push(Value::Integer(after_id));
+    }
+
+    FilterSql {
+        source: if searching {
+            "mails JOIN mails_fts ON mails_fts.rowid = mails.id"
+        } else {
+            "mails"
+        },
+        where_clause: if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        },
+        params,
+        searching,
+    }
+}
+
+/// FTS5 query requiring every term, each matched as a prefix
+fn fts_query(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text indexed for a mail: subject, sender, text body and visible HTML text
+fn index_mail(conn: &Connection, mail: &StoredMail) -> Result<(), DbError> {
+    conn.prepare_cached(
+        "INSERT INTO mails_fts (rowid, subject, sender, body, html, attachments) VALUES (?, ?, ?, ?, ?, '')",
+    )?
+    .execute(rusqlite::params![
+        mail.id,
+        mail.subject,
+        format!("{} {}", mail.from_name, mail.from_address),
+        mail.text,
+        html::strip_tags(&mail.html),
+    ])?;
+    Ok(())
+}
+
+/// Index mails stored before the full-text index existed
+fn backfill_search_index(conn: &Connection) -> Result<(), DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM mails WHERE id NOT IN (SELECT rowid FROM mails_fts)",
+        MAIL_COLUMNS
+    ))?;
+    let mails = stmt
+        .query_map([], mail_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    for mail in &mails {
+        index_mail(conn, mail)?;
+    }
+    conn.execute(
+        "UPDATE mails_fts SET attachments = (
+            SELECT COALESCE(group_concat(filename, ' '), '') FROM attachments WHERE mail_id = mails_fts.rowid
+        ) WHERE rowid IN (SELECT DISTINCT mail_id FROM attachments) AND attachments = ''",
+        [],
+    )?;
+    if !mails.is_empty() {
+        println!("[DB] Indexed {} mails for full-text search", mails.len());
+    }
+    Ok(())
+}
+
+/// Case-insensitive substring pattern for `LIKE ? ESCAPE '\'`
+fn like_pattern(value: &str) -> Value {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    Value::Text(format!("%{}%", escaped))
+}
+
+fn mail_exists(conn: &Connection, id: i64) -> Result<bool, DbError> {
+    Ok(conn
+        .prepare_cached("SELECT 1 FROM mails WHERE id = ?")?
+        .exists([id])?)
+}
+
+/// A mail with its attachments and recipients
+fn load_mail(conn: &Connection, id: i64) -> Result<Option<StoredMail>, DbError> {
+    let mail = conn
+        .prepare_cached(&format!("SELECT {} FROM mails WHERE id = ?", MAIL_COLUMNS))?
+        .query_row([id], mail_from_row)
+        .optional()?;
+    match mail {
+        Some(mut mail) => {
+            load_details(conn, &mut mail)?;
+            Ok(Some(mail))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Fill in the attachments and recipients of a mail
+fn load_details(conn: &Connection, mail: &mut StoredMail) -> Result<(), DbError> {
+    mail.attachments = conn
+        .prepare_cached(
+            "SELECT id, mail_id, filename, content_type, content_disposition, size_bytes, file_url FROM attachments WHERE mail_id = ?",
+        )?
+        .query_map([mail.id], |row| {
+            Ok(Attachment {
+                id: row.get(0)?,
+                mail_id: row.get(1)?,
+                filename: row.get(2)?,
+                content_type: row.get(3)?,
+                content_disposition: row.get(4)?,
+                size_bytes: row.get(5)?,
+                file_url: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut stmt =
+        conn.prepare_cached("SELECT kind, name, address FROM recipients WHERE mail_id = ? ORDER BY id")?;
+    let recipients = stmt.query_map([mail.id], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            Recipient {
+                name: row.get(1)?,
+                address: row.get(2)?,
+            },
+        ))
+    })?;
+    for recipient in recipients {
+        let (kind, recipient) = recipient?;
+        match kind.as_str() {
+            "to" => mail.to.push(recipient),
+            "cc" => mail.cc.push(recipient),
+            "bcc" => mail.bcc.push(recipient),
+            "reply_to" => mail.reply_to.push(recipient),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn mail_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredMail> {
+    Ok(StoredMail {
+        id: row.get(0)?,
+        from_address: row.get(1)?,
+        from_name: row.get(2)?,
+        to_address: row.get(3)?,
+        to_name: row.get(4)?,
+        subject: row.get(5)?,
+        html: row.get(6)?,
+        text: row.get(7)?,
+        date: row.get(8)?,
+        is_read: row.get::<_, i64>(9)? != 0,
+        parse_warnings: json_list(row.get(10)?),
+        envelope: Envelope {
+            helo_domain: row.get::<_, Option<String>>(11)?.unwrap_or_default(),
+            mail_from: row.get::<_, Option<String>>(12)?.unwrap_or_default(),
+            rcpt_to: json_list(row.get(13)?),
+            is_8bit: row.get::<_, Option<i64>>(14)?.unwrap_or_default() != 0,
+            auth_user: row.get(15)?,
+            tls: match (row.get(16)?, row.get(17)?) {
+                (Some(protocol), Some(cipher)) => Some(TlsInfo { protocol, cipher }),
+                _ => None,
+            },
+        },
+        to: Vec::new(), // Recipients and attachments are loaded separately
+        cc: Vec::new(),
+        bcc: Vec::new(),
+        reply_to: Vec::new(),
+        attachments: Vec::new(),
+    })
+}
+
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    let secret: Option<String> = row.get(2)?;
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        signed: secret.is_some(),
+        secret,
+        events: json_list(row.get(3)?),
+        created_at: row.get(4)?,
+    })
+}
+
+fn json_list(json: Option<String>) -> Vec<String> {
+    json.and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
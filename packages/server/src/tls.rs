@@ -0,0 +1,108 @@
+use crate::models::TlsInfo;
+use rustls::{ProtocolVersion, ServerConfig, ServerConnection, StreamOwned};
+use rustls_pki_types::pem::{self, PemObject};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Names the generated certificate is valid for
+const SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+const SELF_SIGNED_CERT_FILE: &str = "cert.pem";
+const SELF_SIGNED_KEY_FILE: &str = "key.pem";
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, io::Error),
+    Pem(PathBuf, pem::Error),
+    Generate(rcgen::Error),
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            TlsError::Pem(path, e) => write!(f, "{}: invalid PEM: {:?}", path.display(), e),
+            TlsError::Generate(e) => write!(f, "failed to generate a certificate: {}", e),
+            TlsError::Rustls(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+/// Server configuration for a PEM certificate chain and private key
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(cert_path.to_path_buf(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| TlsError::Pem(key_path.to_path_buf(), e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Server configuration for the self-signed certificate kept in `dir`,
+/// generated on first use so that clients can pin it across restarts
+pub fn self_signed_config(dir: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let cert_path = dir.join(SELF_SIGNED_CERT_FILE);
+    let key_path = dir.join(SELF_SIGNED_KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        let names: Vec<String> = SELF_SIGNED_NAMES.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).map_err(TlsError::Generate)?;
+        std::fs::create_dir_all(dir).map_err(|e| TlsError::Io(dir.to_path_buf(), e))?;
+        write_private(&key_path, generated.key_pair.serialize_pem().as_bytes())
+            .map_err(|e| TlsError::Io(key_path.clone(), e))?;
+        std::fs::write(&cert_path, generated.cert.pem())
+            .map_err(|e| TlsError::Io(cert_path.clone(), e))?;
+        println!("[TLS] Generated a self-signed certificate in {}", cert_path.display());
+    }
+    server_config(&cert_path, &key_path)
+}
+
+/// Write `contents` to a file only its owner can read, on Unix
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+/// Run the server side of a TLS handshake over `stream`
+pub fn accept(
+    config: Arc<ServerConfig>,
+    stream: TcpStream,
+) -> io::Result<(StreamOwned<ServerConnection, TcpStream>, Option<TlsInfo>)> {
+    let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+    let mut tls = StreamOwned::new(conn, stream);
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+    let info = session_info(&tls.conn);
+    Ok((tls, info))
+}
+
+/// Protocol and cipher suite negotiated on a connection, once the handshake is done
+fn session_info(conn: &ServerConnection) -> Option<TlsInfo> {
+    let protocol = match conn.protocol_version()? {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+        other => format!("{:?}", other),
+    };
+    let suite = conn.negotiated_cipher_suite()?.suite();
+    let cipher = suite.as_str().map(str::to_string).unwrap_or_else(|| format!("{:?}", suite));
+    Some(TlsInfo { protocol, cipher })
+}
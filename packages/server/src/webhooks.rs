@@ -0,0 +1,197 @@
+use crate::events::BusEvent;
+use crate::models::{Webhook, WebhookDelivery};
+use crate::storage::Storage;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Event names a webhook can subscribe to
+pub const EVENTS: &[&str] = &["mail.created", "mail.deleted", "inbox.cleared", "mails.deleted"];
+
+/// Attempts per delivery, including the first one
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled after each failed attempt
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Time allowed for a target to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts mail events to the registered webhooks, retrying failed deliveries
+/// and logging every attempt
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    storage: Storage,
+    client: reqwest::Client,
+    max_attempts: u32,
+    initial_backoff: Duration,
+}
+
+impl WebhookDispatcher {
+    pub fn new(storage: Storage) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("mail-server-dev/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .unwrap_or_default();
+        Self {
+            storage,
+            client,
+            max_attempts: MAX_ATTEMPTS,
+            initial_backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Use another retry schedule than `MAX_ATTEMPTS` starting at `INITIAL_BACKOFF`
+    pub fn with_retries(mut self, max_attempts: u32, initial_backoff: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Deliver events from the bus until it closes
+    pub async fn run(self, mut receiver: broadcast::Receiver<BusEvent>) {
+        loop {
+            match receiver.recv().await {
+                Ok(published) => self.dispatch(published).await,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    eprintln!("[Webhook] Fell behind, {} events were not delivered", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Start a delivery to every webhook interested in `event`
+    async fn dispatch(&self, published: BusEvent) {
+        let event = &published.event;
+        if !EVENTS.contains(&event.name()) {
+            return;
+        }
+        let storage = self.storage.clone();
+        let webhooks = match tokio::task::spawn_blocking(move || storage.list_webhooks()).await {
+            Ok(Ok(webhooks)) => webhooks,
+            Ok(Err(e)) => return eprintln!("[Webhook] Failed to load webhooks: {}", e),
+            Err(e) => return eprintln!("[Webhook] Task failed: {}", e),
+        };
+        // The mail concerned, or what live clients receive for other events
+        let body = match event.mail() {
+            Some(mail) => serde_json::to_vec(mail),
+            None => serde_json::to_vec(&published.to_wire().data),
+        };
+        let body = match body {
+            Ok(body) => Arc::new(body),
+            Err(e) => return eprintln!("[Webhook] Failed to serialize mail: {}", e),
+        };
+        let mail_id = event.mail().map(|mail| mail.id);
+
+        for webhook in webhooks.into_iter().filter(|webhook| webhook.wants(event)) {
+            let this = self.clone();
+            let body = Arc::clone(&body);
+            let event_name = event.name();
+            tokio::spawn(async move { this.deliver(webhook, event_name, mail_id, body).await });
+        }
+    }
+
+    /// POST `body` to one webhook until it answers with a 2xx status or attempts
+    /// run out, returning whether it succeeded
+    async fn deliver(
+        &self,
+        webhook: Webhook,
+        event_name: &str,
+        mail_id: Option<i64>,
+        body: Arc<Vec<u8>>,
+    ) -> bool {
+        let signature = webhook.secret.as_deref().map(|secret| sign(secret, &body));
+        let mut backoff = self.initial_backoff;
+
+        for attempt in 1..=self.max_attempts {
+            let mut request = self
+                .client
+                .post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Webhook-Event", event_name)
+                .header("X-Webhook-Attempt", attempt.to_string())
+                .body(body.to_vec());
+            if let Some(signature) = &signature {
+                request = request.header("X-Webhook-Signature", signature);
+            }
+
+            let started = Instant::now();
+            let result = request.send().await;
+            let (status, error) = match result {
+                Ok(response) if response.status().is_success() => {
+                    (Some(response.status().as_u16()), None)
+                }
+                Ok(response) => (
+                    Some(response.status().as_u16()),
+                    Some(format!("Unexpected status {}", response.status())),
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let succeeded = error.is_none();
+            if let Some(error) = &error {
+                let subject = mail_id.map_or_else(|| "the inbox".to_string(), |id| format!("mail {}", id));
+                eprintln!(
+                    "[Webhook] Delivery of {} for {} to {} failed (attempt {}/{}): {}",
+                    event_name, subject, webhook.url, attempt, self.max_attempts, error
+                );
+            }
+
+            let mut delivery = WebhookDelivery {
+                id: 0,
+                webhook_id: webhook.id,
+                url: webhook.url.clone(),
+                event: event_name.to_string(),
+                mail_id,
+                attempt,
+                status,
+                error,
+                duration_ms: started.elapsed().as_millis() as i64,
+                delivered_at: String::new(),
+            };
+            let storage = self.storage.clone();
+            match tokio::task::spawn_blocking(move || storage.record_delivery(&mut delivery)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("[Webhook] Failed to log delivery: {}", e),
+                Err(e) => eprintln!("[Webhook] Task failed: {}", e),
+            }
+
+            if succeeded {
+                return true;
+            }
+            if attempt < self.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        false
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `body`, sent as `X-Webhook-Signature`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Register the comma-separated `urls` from the configuration, skipping those
+/// already registered by a previous run
+pub fn register_configured(
+    storage: &Storage,
+    urls: &str,
+    secret: Option<&str>,
+) -> Result<(), crate::db::DbError> {
+    let existing = storage.list_webhooks()?;
+    for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+        if !existing.iter().any(|webhook| webhook.url == url) {
+            storage.insert_webhook(url, secret, &[])?;
+            println!("[Webhook] Registered {}", url);
+        }
+    }
+    Ok(())
+}
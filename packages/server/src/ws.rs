@@ -0,0 +1,217 @@
+use crate::api_error::ApiError;
+use crate::db::DbError;
+use crate::events::{EventBus, WireEvent};
+use crate::models::MailEvent;
+use crate::rest_server::{delete_and_publish, set_read_and_publish};
+use crate::storage::Storage;
+use axum::extract::ws::{Message, WebSocket};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
+use tokio::sync::broadcast;
+
+/// Messages accepted from a WebSocket client, tagged by `type`. `request_id`
+/// is echoed back in the matching `result` or `error` reply.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Only receive events for mails matching these filters, replacing previous ones
+    Subscribe {
+        request_id: Option<String>,
+        #[serde(flatten)]
+        filter: SubscribeParams,
+    },
+    MarkRead {
+        request_id: Option<String>,
+        id: i64,
+        #[serde(default = "default_is_read")]
+        is_read: bool,
+    },
+    Delete {
+        request_id: Option<String>,
+        id: i64,
+    },
+}
+
+fn default_is_read() -> bool {
+    true
+}
+
+/// Subscription filters, also accepted as query parameters of `/api/ws`
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscribeParams {
+    /// Address of any recipient, in the headers or the SMTP envelope
+    pub recipient: Option<String>,
+    /// Case-insensitive regular expression matched against the subject
+    pub subject: Option<String>,
+}
+
+/// Messages sent to a WebSocket client, tagged by `type`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// `id`, `event` and `data` as in the SSE stream
+    Event(WireEvent),
+    /// Events were missed, the client should reload its data
+    Resync,
+    Result {
+        request_id: Option<String>,
+    },
+    Error {
+        request_id: Option<String>,
+        message: String,
+    },
+}
+
+/// Compiled subscription filters
+#[derive(Default)]
+struct Subscription {
+    recipient: Option<String>,
+    subject: Option<Regex>,
+}
+
+impl Subscription {
+    fn new(params: SubscribeParams) -> Result<Self, String> {
+        let subject = params
+            .subject
+            .map(|pattern| {
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("Invalid subject pattern: {}", e))
+            })
+            .transpose()?;
+        Ok(Self {
+            recipient: params.recipient,
+            subject,
+        })
+    }
+
+    /// Whether the event concerns a matching mail. Events about the whole mailbox always match.
+    fn matches(&self, event: &MailEvent) -> bool {
+        let Some(mail) = event.mail() else {
+            return true;
+        };
+        let recipient_matches = self.recipient.as_ref().is_none_or(|wanted| {
+            mail.to
+                .iter()
+                .chain(&mail.cc)
+                .chain(&mail.bcc)
+                .map(|recipient| &recipient.address)
+                .chain(&mail.envelope.rcpt_to)
+                .any(|address| address.eq_ignore_ascii_case(wanted))
+        });
+        let subject_matches = self
+            .subject
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&mail.subject));
+        recipient_matches && subject_matches
+    }
+}
+
+/// Serve one WebSocket connection: stream the events of the bus that match the
+/// subscription and run the commands sent by the client
+pub async fn serve(mut socket: WebSocket, storage: Storage, events: EventBus, params: SubscribeParams) {
+    let mut rx = events.subscribe();
+    let mut subscription = match Subscription::new(params) {
+        Ok(subscription) => subscription,
+        Err(message) => {
+            let _ = send(&mut socket, &ServerMessage::Error { request_id: None, message }).await;
+            return;
+        }
+    };
+
+    loop {
+        let reply = tokio::select! {
+            published = rx.recv() => match published {
+                Ok(published) if subscription.matches(&published.event) => {
+                    ServerMessage::Event(published.to_wire())
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => ServerMessage::Resync,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle_command(&text, &storage, &events, &mut subscription).await;
+                    if send(&mut socket, &reply).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // Pings are answered by axum
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+        };
+        if send(&mut socket, &reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_command(
+    text: &str,
+    storage: &Storage,
+    events: &EventBus,
+    subscription: &mut Subscription,
+) -> ServerMessage {
+    let message: ClientMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            return ServerMessage::Error {
+                request_id: None,
+                message: format!("Invalid message: {}", e),
+            };
+        }
+    };
+
+    let (request_id, outcome) = match message {
+        ClientMessage::Subscribe { request_id, filter } => {
+            let outcome = Subscription::new(filter).map(|new| *subscription = new);
+            (request_id, outcome)
+        }
+        ClientMessage::MarkRead { request_id, id, is_read } => {
+            let (storage, events) = (storage.clone(), events.clone());
+            let found = tokio::task::spawn_blocking(move || {
+                set_read_and_publish(&storage, &events, id, is_read)
+            })
+            .await;
+            (request_id, command_outcome(id, found.map(|found| found.map(|mail| mail.is_some()))))
+        }
+        ClientMessage::Delete { request_id, id } => {
+            let (storage, events) = (storage.clone(), events.clone());
+            let found = tokio::task::spawn_blocking(move || delete_and_publish(&storage, &events, id)).await;
+            (request_id, command_outcome(id, found))
+        }
+    };
+
+    match outcome {
+        Ok(()) => ServerMessage::Result { request_id },
+        Err(message) => ServerMessage::Error { request_id, message },
+    }
+}
+
+/// Turn the result of a blocking command on mail `id` into a reply, worded like
+/// the REST API's errors. The cause of internal errors stays in the logs.
+fn command_outcome(
+    id: i64,
+    found: Result<Result<bool, DbError>, tokio::task::JoinError>,
+) -> Result<(), String> {
+    let error = match found {
+        Ok(Ok(true)) => return Ok(()),
+        Ok(Ok(false)) => ApiError::MailNotFound(id),
+        Ok(Err(e)) => ApiError::from(e),
+        Err(e) => ApiError::from(e),
+    };
+    if let ApiError::Internal(cause) = &error {
+        eprintln!("[WS] Command on mail {} failed: {}", id, cause);
+    }
+    Err(error.body().message)
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(json)).await
+}
@@ -76,6 +76,24 @@ export const sidebarContent = style({
     overflowY: "auto",
 });
 
+export const sidebarLoadMore = style({
+    width: "100%",
+    padding: "0.75rem",
+    background: "none",
+    border: "none",
+    borderTop: `1px solid ${vars.color.border}`,
+    color: vars.color.mutedForeground,
+    cursor: "pointer",
+    selectors: {
+        '&:hover:not(:disabled)': {
+            color: vars.color.foreground,
+        },
+        '&:disabled': {
+            cursor: 'default',
+        },
+    },
+});
+
 export const sidebarHeaderUnreadCount = style({
     backgroundColor: vars.color.primary,
     color: vars.color.primaryForeground,
@@ -1,19 +1,30 @@
-import { sidebarStyles, sidebarHeader, sidebarHeaderTitle, sidebarContent, sidebarHeaderUnreadCount, sidebarOverlay, sidebarClose } from './sidebar.css';
+import { sidebarStyles, sidebarHeader, sidebarHeaderTitle, sidebarContent, sidebarHeaderUnreadCount, sidebarOverlay, sidebarClose, sidebarLoadMore } from './sidebar.css';
 import { MailsList } from '../mails/components/mails-list';
-import { MailList } from '../../types/mail.type';
-import { useQuery, useQueryClient } from '@tanstack/react-query';
+import { MailPage } from '../../types/mail.type';
+import { useInfiniteQuery, useQueryClient } from '@tanstack/react-query';
 import { useState } from 'preact/hooks';
 import { useEffect } from 'preact/hooks';
 import { useSidebarStore } from '../../lib/sidebar-store';
+import { apiFetch } from '../../lib/api';
+
+const PAGE_SIZE = 50;
 
 export function Sidebar() {
     const queryClient = useQueryClient();
-    const { data, isLoading, error } = useQuery<MailList>({
+    const { data, isLoading, error, hasNextPage, fetchNextPage, isFetchingNextPage } = useInfiniteQuery({
         queryKey: ['mails'],
-        queryFn: () => fetch('/api/mails').then(res => res.json()),
+        queryFn: ({ pageParam }): Promise<MailPage> =>
+            apiFetch(`/api/mails?limit=${PAGE_SIZE}&offset=${pageParam}`).then(res => res.json()),
+        initialPageParam: 0,
+        // The next page starts where this one ended, until every mail is loaded
+        getNextPageParam: (last: MailPage) => {
+            const next = last.offset + last.items.length;
+            return next < last.total ? next : undefined;
+        },
     })
 
-    const unreadCount = data?.filter(mail => !mail.is_read).length || 0;
+    const mails = data?.pages.flatMap(page => page.items);
+    const unreadCount = data?.pages[0]?.unread || 0;
 
     const [notif, setNotif] = useState(false);
     const isOpen = useSidebarStore(s => s.isOpen);
@@ -28,8 +39,9 @@ export function Sidebar() {
 
     useEffect(() => {
         const es = new EventSource('/api/events');
-        es.onmessage = (event) => {
-            queryClient.invalidateQueries({ queryKey: ['mails'] });
+        const refresh = () => queryClient.invalidateQueries({ queryKey: ['mails'] });
+        es.addEventListener('mail.created', (event) => {
+            refresh();
             try {
                 const mail = JSON.parse(event.data);
                 if (mail && mail.is_read === false) {
@@ -43,7 +55,14 @@ export function Sidebar() {
                     }
                 }
             } catch {}
-        };
+        });
+        es.addEventListener('mail.updated', refresh);
+        es.addEventListener('mail.deleted', refresh);
+        es.addEventListener('mails.updated', refresh);
+        es.addEventListener('mails.deleted', refresh);
+        // The inbox was emptied or events were missed, reload everything
+        es.addEventListener('inbox.cleared', () => queryClient.invalidateQueries());
+        es.addEventListener('resync', () => queryClient.invalidateQueries());
         return () => es.close();
     }, [queryClient]);
 
@@ -80,7 +99,16 @@ export function Sidebar() {
                     </button>
                 </div>
                 <div class={sidebarContent}>
-                    <MailsList data={data} isLoading={isLoading} error={error} />
+                    <MailsList data={mails} isLoading={isLoading} error={error} />
+                    {hasNextPage && (
+                        <button
+                            class={sidebarLoadMore}
+                            onClick={() => fetchNextPage()}
+                            disabled={isFetchingNextPage}
+                        >
+                            {isFetchingNextPage ? 'Loading…' : 'Load more'}
+                        </button>
+                    )}
                 </div>
             </aside>
         </>
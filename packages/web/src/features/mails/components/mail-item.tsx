@@ -1,13 +1,13 @@
-import { Mail, MailList } from "../../../types/mail.type";
+import { MailPage, MailSummary } from "../../../types/mail.type";
 import { mailStyles, mailHeader, mailHeaderTop, mailSender, mailDate, mailSubject, mailTo, mailPreview, mailFadeOut } from "./mail-item.css";
 import { useLocation } from "preact-iso";
-import { useQueryClient } from "@tanstack/react-query";
+import { InfiniteData, useQueryClient } from "@tanstack/react-query";
 import { useEffect, useMemo, useState } from "preact/hooks";
 import { formatRelativeTimeFormat } from "../../../utils/date";
 import { useSidebarStore } from "../../../lib/sidebar-store";
 
 export type MailItemProps = {
-    mail: Mail;
+    mail: MailSummary;
 }
 
 export const MailItem = ({ mail }: MailItemProps) => {
@@ -42,7 +42,14 @@ export const MailItem = ({ mail }: MailItemProps) => {
             data-active={isActive}
             onClick={async () => {
                 location.route(`/mail/${mail.id}`);
-                queryClient.setQueryData(['mails'], (old: MailList) => old.map(m => m.id === mail.id ? {...m, is_read: true} : m));
+                queryClient.setQueryData(['mails'], (old: InfiniteData<MailPage>) => old && ({
+                    ...old,
+                    pages: old.pages.map(page => ({
+                        ...page,
+                        unread: mail.is_read ? page.unread : Math.max(page.unread - 1, 0),
+                        items: page.items.map(m => m.id === mail.id ? {...m, is_read: true} : m),
+                    })),
+                }));
                 closeSidebar();
             }}
         >
@@ -53,7 +60,7 @@ export const MailItem = ({ mail }: MailItemProps) => {
             </div>
             <span class={mailSubject}>{mail.subject}</span>
             </div>
-            <span class={mailPreview}>{mail.text}</span>
+            <span class={mailPreview}>{mail.preview}</span>
         </li>
     );
 }
\ No newline at end of file
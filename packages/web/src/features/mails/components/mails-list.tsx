@@ -4,7 +4,7 @@ import { MailItem } from "./mail-item";
 import { useEffect, useRef, useState } from "preact/hooks";
 
 type MailsListProps = { 
-    data: MailList;
+    data: MailList | undefined;
     isLoading: boolean;
     error: Error | null;
 }
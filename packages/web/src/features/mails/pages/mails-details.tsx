@@ -18,6 +18,7 @@ import {
 import dayjs from 'dayjs';
 import { useLocation } from "preact-iso";
 import { Attachments } from "../components/attachments";
+import { apiFetch } from "../../../lib/api";
 
 type MailDetailsProps = {
     id: string;
@@ -28,11 +29,11 @@ export const MailDetails = ({ id }: MailDetailsProps) => {
     const location = useLocation()
     const { data, isLoading, error } = useQuery<Mail>({
         queryKey: ['mail', id],
-        queryFn: () => fetch(`/api/mails/${id}`).then(res => res.json())
+        queryFn: () => apiFetch(`/api/mails/${id}?mark_read=true`).then(res => res.json())
     })
 
     const deleteMail = useMutation({
-        mutationFn: () => fetch(`/api/mails/${id}`, {
+        mutationFn: () => apiFetch(`/api/mails/${id}`, {
             method: 'DELETE',
         }),
         onSuccess: () => {
@@ -86,6 +87,8 @@ export const MailDetails = ({ id }: MailDetailsProps) => {
                 <span class={mailHeaderSubject}><strong>Subject :</strong> {data.subject}</span>
                 <div class={mailMeta}>
                     <span>{dayjs(data.date).format('DD/MM/YYYY HH:mm')}</span>
+                    {data.envelope.auth_user && <span>Authenticated as {data.envelope.auth_user}</span>}
+                    {data.envelope.tls && <span title={data.envelope.tls.cipher}>{data.envelope.tls.protocol}</span>}
                 </div>
             </div>
             <div class={mailBodyContainer}>
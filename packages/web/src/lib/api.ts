@@ -0,0 +1,21 @@
+/** Error body answered by the API for any non-2xx status */
+export type ApiErrorBody = {
+  code: string;
+  message: string;
+};
+
+export class ApiError extends Error {
+  constructor(public status: number, public code: string, message: string) {
+    super(message);
+  }
+}
+
+/** Fetch an API endpoint, throwing an `ApiError` unless it succeeds */
+export async function apiFetch(input: string, init?: RequestInit): Promise<Response> {
+  const res = await fetch(input, init);
+  if (!res.ok) {
+    const body: Partial<ApiErrorBody> = await res.json().catch(() => ({}));
+    throw new ApiError(res.status, body.code ?? 'unknown', body.message ?? res.statusText);
+  }
+  return res;
+}
@@ -8,6 +8,25 @@ export type Attachment = {
     file_url: string;
 }
 
+export type Recipient = {
+    name: string;
+    address: string;
+}
+
+export type TlsInfo = {
+    protocol: string;
+    cipher: string;
+}
+
+export type Envelope = {
+    helo_domain: string;
+    mail_from: string;
+    rcpt_to: string[];
+    is_8bit: boolean;
+    auth_user: string | null;
+    tls: TlsInfo | null;
+}
+
 export type Mail = {
     id: number;
     from_address: string;
@@ -19,7 +38,40 @@ export type Mail = {
     text: string;
     date: string;
     is_read: boolean;
+    parse_warnings: string[];
+    envelope: Envelope;
+    to: Recipient[];
+    cc: Recipient[];
+    bcc: Recipient[];
+    reply_to: Recipient[];
     attachments: Attachment[];
 }
 
-export type MailList = Mail[];
\ No newline at end of file
+export type MailSummary = {
+    id: number;
+    from_address: string;
+    from_name: string;
+    to_address: string;
+    to_name: string;
+    subject: string;
+    preview: string;
+    date: string;
+    is_read: boolean;
+    attachment_count: number;
+    snippet: string | null;
+}
+
+export type MailPage = {
+    items: MailSummary[];
+    total: number;
+    unread: number;
+    limit: number;
+    offset: number;
+}
+
+export type MailList = MailSummary[];
+export type MailHeader = {
+    name: string;
+    raw_value: string;
+    value: string;
+}
@@ -1,11 +1,21 @@
 import nodemailer from 'nodemailer';
+import { existsSync, readFileSync } from 'node:fs';
+
+// Certificate the server generated on first start: ./tls with docker-compose,
+// packages/server/tls with `bun run dev`, or SMTP_TLS_CA when it uses its own
+const caPath = process.env.SMTP_TLS_CA
+    ?? ['tls/cert.pem', 'packages/server/tls/cert.pem'].find(path => existsSync(path));
+if (!caPath) {
+    throw new Error('No server certificate found, start the server once or set SMTP_TLS_CA');
+}
 
 const transporter = await nodemailer.createTransport({
     host: '127.0.0.1',
     port: 1025,
     secure: false,
     tls: {
-        rejectUnauthorized: false
+        ca: readFileSync(caPath),
+        servername: 'localhost'
     }
 });
 